@@ -0,0 +1,162 @@
+use crate::world::canvas::Canvas;
+use crate::world::change::ChangeEvent;
+use crate::world::color::Color;
+use crate::world::history::History;
+use crate::world::persistence::encode_history;
+use crate::world::World;
+use std::time::Instant;
+
+const USAGE: &str = "\
+Usage: Pixie bench-snapshots [options]
+
+Compares full-canvas snapshots with delta-encoded snapshots on the same
+randomly generated paint history.
+
+Options:
+  --size <N>                 canvas width and height (default 512)
+  --changes <N>              number of paints to generate (default 100000)
+  --interval <N>             changes between snapshots (default 100)
+  --keyframes <N>            snapshots per keyframe for delta encoding (default 16)
+  --colors <N>               distinct colors to paint with (default 16)";
+
+struct Options {
+    size: usize,
+    changes: usize,
+    interval: usize,
+    keyframes: usize,
+    colors: usize,
+}
+
+/// Measurements for one snapshot encoding
+struct Report {
+    snapshots: usize,
+    keyframes: usize,
+    memory_bytes: usize,
+    file_bytes: usize,
+    build_ms: f64,
+    lookup_us: f64,
+}
+
+/// Entry point for `Pixie bench-snapshots ...`; `args` excludes the subcommand itself
+pub fn run(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let options = parse_args(args).map_err(|e| format!("{}\n\n{}", e, USAGE))?;
+    let events = generate_paints(&options);
+    println!(
+        "{} paint(s) on a {}x{} canvas, a snapshot every {} change(s)",
+        options.changes, options.size, options.size, options.interval
+    );
+
+    let full = measure(&options, &events, 1)?;
+    let delta = measure(&options, &events, options.keyframes)?;
+
+    println!();
+    println!("{:<22}{:>16}{:>16}", "", "full", format!("delta (1/{})", options.keyframes));
+    println!("{:<22}{:>16}{:>16}", "snapshots", full.snapshots, delta.snapshots);
+    println!("{:<22}{:>16}{:>16}", "keyframes", full.keyframes, delta.keyframes);
+    println!("{:<22}{:>16}{:>16}", "snapshot memory", format_bytes(full.memory_bytes), format_bytes(delta.memory_bytes));
+    println!("{:<22}{:>16}{:>16}", "history file", format_bytes(full.file_bytes), format_bytes(delta.file_bytes));
+    println!("{:<22}{:>16.1}{:>16.1}", "build time (ms)", full.build_ms, delta.build_ms);
+    println!("{:<22}{:>16.1}{:>16.1}", "canvas_at (us)", full.lookup_us, delta.lookup_us);
+    println!();
+    println!(
+        "Delta encoding uses {:.1}% of the snapshot memory and {:.1}% of the file size",
+        percent(delta.memory_bytes, full.memory_bytes),
+        percent(delta.file_bytes, full.file_bytes)
+    );
+    Ok(())
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        size: 512,
+        changes: 100_000,
+        interval: 100,
+        keyframes: 16,
+        colors: 16,
+    };
+
+    let mut rest = args.iter();
+    while let Some(flag) = rest.next() {
+        let value = rest.next().ok_or_else(|| format!("Missing value for {}", flag))?;
+        let parsed: usize = value
+            .parse()
+            .ok()
+            .filter(|&n| n > 0)
+            .ok_or_else(|| format!("Invalid value for {}: {}", flag, value))?;
+        match flag.as_str() {
+            "--size" => options.size = parsed,
+            "--changes" => options.changes = parsed,
+            "--interval" => options.interval = parsed,
+            "--keyframes" => options.keyframes = parsed,
+            "--colors" => options.colors = parsed.min(0xFFFFFF),
+            _ => return Err(format!("Unknown option {}", flag)),
+        }
+    }
+
+    Ok(options)
+}
+
+/// Deterministic pseudo-random paints, so both encodings see the same history
+fn generate_paints(options: &Options) -> Vec<ChangeEvent> {
+    let colors: Vec<Color> = (0..options.colors)
+        .map(|i| {
+            let [_, r, g, b] = ((i * 0xFFFFFF / options.colors) as u32).to_be_bytes();
+            Color::from_rgb([r, g, b])
+        })
+        .collect();
+
+    let mut state: u64 = 0x5EED;
+    let mut next = move |bound: usize| {
+        state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        ((state >> 33) as usize) % bound
+    };
+
+    (0..options.changes)
+        .map(|_| ChangeEvent::Paint {
+            x: next(options.size),
+            y: next(options.size),
+            color: colors[next(colors.len())],
+        })
+        .collect()
+}
+
+fn measure(options: &Options, events: &[ChangeEvent], keyframe_interval: usize) -> Result<Report, Box<dyn std::error::Error>> {
+    let canvas = Canvas::new(options.size, options.size).map_err(|e| format!("{:?}", e))?;
+    let mut world = World::from(History::new(options.interval, keyframe_interval, &canvas));
+
+    let start = Instant::now();
+    for event in events {
+        world.apply_event(event.clone(), None).map_err(|e| format!("{:?}", e))?;
+    }
+    let build_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+    // Look up a spread of change counts, including ones far from a keyframe
+    let lookups: Vec<usize> = (0..200).map(|i| i * options.changes / 200 + i % options.interval).collect();
+    let start = Instant::now();
+    for &count in &lookups {
+        std::hint::black_box(world.history.canvas_at(count));
+    }
+    let lookup_us = start.elapsed().as_secs_f64() * 1_000_000.0 / lookups.len() as f64;
+
+    let history = &world.history;
+    Ok(Report {
+        snapshots: history.snapshots.len(),
+        keyframes: history.snapshots.iter().filter(|s| s.is_keyframe()).count(),
+        memory_bytes: history.snapshot_bytes(),
+        file_bytes: encode_history(history)?.len(),
+        build_ms,
+        lookup_us,
+    })
+}
+
+fn format_bytes(bytes: usize) -> String {
+    match bytes {
+        b if b >= 1 << 20 => format!("{:.1} MiB", b as f64 / (1 << 20) as f64),
+        b if b >= 1 << 10 => format!("{:.1} KiB", b as f64 / (1 << 10) as f64),
+        b => format!("{} B", b),
+    }
+}
+
+fn percent(part: usize, whole: usize) -> f64 {
+    part as f64 * 100.0 / whole.max(1) as f64
+}
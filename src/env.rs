@@ -1,13 +1,40 @@
+use crate::server::permissions::Permission;
 use std::sync::OnceLock;
 
 static ADMIN_TOKEN: OnceLock<String> = OnceLock::new();
+static SESSION_SECRET: OnceLock<Option<String>> = OnceLock::new();
 static DEFAULT_CANVAS_WIDTH: OnceLock<usize> = OnceLock::new();
 static DEFAULT_CANVAS_HEIGHT: OnceLock<usize> = OnceLock::new();
 static DEFAULT_SNAPSHOT_INTERVAL: OnceLock<usize> = OnceLock::new();
+static SNAPSHOT_KEYFRAME_INTERVAL: OnceLock<usize> = OnceLock::new();
 static PERSISTENCE_PATH: OnceLock<String> = OnceLock::new();
+static ROOMS_DIR: OnceLock<String> = OnceLock::new();
 static AUTOSAVE_INTERVAL: OnceLock<u64> = OnceLock::new();
 static RATE_LIMIT_TOKENS: OnceLock<f64> = OnceLock::new();
 static RATE_LIMIT_REFILL_RATE_MS: OnceLock<f64> = OnceLock::new();
+static PING_RATE_LIMIT_TOKENS: OnceLock<f64> = OnceLock::new();
+static PING_RATE_LIMIT_REFILL_RATE_MS: OnceLock<f64> = OnceLock::new();
+static QUERY_RATE_LIMIT_TOKENS: OnceLock<f64> = OnceLock::new();
+static QUERY_RATE_LIMIT_REFILL_RATE_MS: OnceLock<f64> = OnceLock::new();
+static IMAGE_RATE_LIMIT_TOKENS: OnceLock<f64> = OnceLock::new();
+static IMAGE_RATE_LIMIT_REFILL_RATE_MS: OnceLock<f64> = OnceLock::new();
+static RATE_LIMIT_IP_MULTIPLIER: OnceLock<f64> = OnceLock::new();
+static RATE_LIMIT_IPV6_PREFIX: OnceLock<u32> = OnceLock::new();
+static MAX_CONNECTIONS_PER_IP: OnceLock<usize> = OnceLock::new();
+static BROADCAST_TICK_MS: OnceLock<u64> = OnceLock::new();
+static RESUME_MAX_CHANGES: OnceLock<usize> = OnceLock::new();
+static WAL_SYNC_INTERVAL_MS: OnceLock<u64> = OnceLock::new();
+static RETAIN_CHANGES: OnceLock<Option<usize>> = OnceLock::new();
+static RETAIN_DAYS: OnceLock<Option<f64>> = OnceLock::new();
+static HISTORY_ARCHIVE_DIR: OnceLock<Option<String>> = OnceLock::new();
+static PALETTE_GC_MIN_UNUSED: OnceLock<usize> = OnceLock::new();
+static PAINT_COOLDOWN_MS: OnceLock<u64> = OnceLock::new();
+static ADMIN_PAINT_COOLDOWN_MS: OnceLock<u64> = OnceLock::new();
+static MODERATOR_PAINT_COOLDOWN_MS: OnceLock<u64> = OnceLock::new();
+static MODERATOR_PERMISSIONS: OnceLock<Vec<Permission>> = OnceLock::new();
+static PLAYER_PERMISSIONS: OnceLock<Vec<Permission>> = OnceLock::new();
+static VIEWER_PERMISSIONS: OnceLock<Vec<Permission>> = OnceLock::new();
+static MAX_CANVAS_PIXELS: OnceLock<usize> = OnceLock::new();
 
 pub fn init() {
     // Load environment variables from .env file
@@ -15,6 +42,11 @@ pub fn init() {
     
     let token = std::env::var("ADMIN_TOKEN")
         .expect("ADMIN_TOKEN must be set in .env file");
+
+    // Signed session tokens are only accepted when a secret is configured
+    let session_secret = std::env::var("SESSION_SECRET")
+        .ok()
+        .filter(|secret| !secret.is_empty());
     
     let width = std::env::var("DEFAULT_CANVAS_WIDTH")
         .ok()
@@ -25,19 +57,53 @@ pub fn init() {
         .ok()
         .and_then(|s| s.parse().ok())
         .unwrap_or(128);
+
+    // Largest canvas, in pixels, that a new room or a resize may ask for
+    let max_canvas_pixels = std::env::var("MAX_CANVAS_PIXELS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or(4096 * 4096);
     
     let snapshot_interval = std::env::var("DEFAULT_SNAPSHOT_INTERVAL")
         .ok()
         .and_then(|s| s.parse().ok())
+        .filter(|&n| n > 0)
         .unwrap_or(100);
+
+    // Every Nth snapshot stores the full canvas, the rest only what changed
+    let keyframe_interval = std::env::var("SNAPSHOT_KEYFRAME_INTERVAL")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or(crate::world::history::DEFAULT_KEYFRAME_INTERVAL);
+
+    // Retention is unlimited unless at least one window is configured
+    let retain_changes = std::env::var("RETAIN_CHANGES")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|&n| n > 0);
+
+    let retain_days = std::env::var("RETAIN_DAYS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|&days: &f64| days > 0.0);
+
+    let history_archive_dir = std::env::var("HISTORY_ARCHIVE_DIR")
+        .ok()
+        .filter(|dir| !dir.is_empty());
     
     let persistence_path = std::env::var("PERSISTENCE_PATH")
         .unwrap_or_else(|_| "history.bin".to_string());
+
+    let rooms_dir = std::env::var("ROOMS_DIR")
+        .unwrap_or_else(|_| "rooms".to_string());
     
+    // Seconds between compacting write-ahead logs into full history files
     let autosave_interval = std::env::var("AUTOSAVE_INTERVAL")
         .ok()
         .and_then(|s| s.parse().ok())
-        .unwrap_or(30);
+        .unwrap_or(300);
 
     let rate_limit_tokens = std::env::var("RATE_LIMIT_TOKENS")
         .ok()
@@ -48,24 +114,160 @@ pub fn init() {
         .ok()
         .and_then(|s| s.parse().ok())
         .unwrap_or(200.0);
+
+    let ping_rate_limit_tokens = std::env::var("PING_RATE_LIMIT_TOKENS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(5.0);
+
+    let ping_rate_limit_refill_rate_ms = std::env::var("PING_RATE_LIMIT_REFILL_RATE_MS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(1000.0);
+
+    let query_rate_limit_tokens = std::env::var("QUERY_RATE_LIMIT_TOKENS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(20.0);
+
+    let query_rate_limit_refill_rate_ms = std::env::var("QUERY_RATE_LIMIT_REFILL_RATE_MS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(100.0);
+
+    // PNG renders over HTTP replay history, so they get a small budget of their own
+    let image_rate_limit_tokens = std::env::var("IMAGE_RATE_LIMIT_TOKENS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(5.0);
+
+    let image_rate_limit_refill_rate_ms = std::env::var("IMAGE_RATE_LIMIT_REFILL_RATE_MS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(2000.0);
+
+    // Clients sharing an address (or IPv6 prefix) share a budget this many times
+    // larger, as a NAT can put a whole network behind one address
+    let rate_limit_ip_multiplier = std::env::var("RATE_LIMIT_IP_MULTIPLIER")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|&n: &f64| n > 0.0)
+        .unwrap_or(10.0);
+
+    let rate_limit_ipv6_prefix = std::env::var("RATE_LIMIT_IPV6_PREFIX")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|&bits| bits <= 128)
+        .unwrap_or(64);
+
+    // 0 allows any number of connections
+    let max_connections_per_ip = std::env::var("MAX_CONNECTIONS_PER_IP")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(10);
+
+    let broadcast_tick_ms = std::env::var("BROADCAST_TICK_MS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|&ms| ms > 0)
+        .unwrap_or(50);
+
+    let resume_max_changes = std::env::var("RESUME_MAX_CHANGES")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(10_000);
+
+    let wal_sync_interval_ms = std::env::var("WAL_SYNC_INTERVAL_MS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|&ms| ms > 0)
+        .unwrap_or(100);
+
+    // Unused palette colors tolerated before compaction rebuilds the palette
+    let palette_gc_min_unused = std::env::var("PALETTE_GC_MIN_UNUSED")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(64);
+
+    // Default milliseconds between paints per role, unless a room overrides them
+    let paint_cooldown_ms = std::env::var("PAINT_COOLDOWN_MS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(0);
+
+    let admin_paint_cooldown_ms = std::env::var("ADMIN_PAINT_COOLDOWN_MS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(0);
+
+    let moderator_paint_cooldown_ms = std::env::var("MODERATOR_PAINT_COOLDOWN_MS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(0);
+
+    // What each role may do; admins can always do everything
+    let moderator_permissions = permissions("MODERATOR_PERMISSIONS", "paint,bypass-ratelimit,selective-revert,rollback,ban");
+    let player_permissions = permissions("PLAYER_PERMISSIONS", "paint");
+    let viewer_permissions = permissions("VIEWER_PERMISSIONS", "");
     
     AUTOSAVE_INTERVAL.set(autosave_interval).expect("Failed to set AUTOSAVE_INTERVAL");
     RATE_LIMIT_TOKENS.set(rate_limit_tokens).expect("Failed to set RATE_LIMIT_TOKENS");
     RATE_LIMIT_REFILL_RATE_MS.set(rate_limit_refill_rate_ms).expect("Failed to set RATE_LIMIT_REFILL_RATE_MS");
+    PING_RATE_LIMIT_TOKENS.set(ping_rate_limit_tokens).expect("Failed to set PING_RATE_LIMIT_TOKENS");
+    PING_RATE_LIMIT_REFILL_RATE_MS.set(ping_rate_limit_refill_rate_ms).expect("Failed to set PING_RATE_LIMIT_REFILL_RATE_MS");
+    QUERY_RATE_LIMIT_TOKENS.set(query_rate_limit_tokens).expect("Failed to set QUERY_RATE_LIMIT_TOKENS");
+    QUERY_RATE_LIMIT_REFILL_RATE_MS.set(query_rate_limit_refill_rate_ms).expect("Failed to set QUERY_RATE_LIMIT_REFILL_RATE_MS");
+    IMAGE_RATE_LIMIT_TOKENS.set(image_rate_limit_tokens).expect("Failed to set IMAGE_RATE_LIMIT_TOKENS");
+    IMAGE_RATE_LIMIT_REFILL_RATE_MS.set(image_rate_limit_refill_rate_ms).expect("Failed to set IMAGE_RATE_LIMIT_REFILL_RATE_MS");
+    RATE_LIMIT_IP_MULTIPLIER.set(rate_limit_ip_multiplier).expect("Failed to set RATE_LIMIT_IP_MULTIPLIER");
+    RATE_LIMIT_IPV6_PREFIX.set(rate_limit_ipv6_prefix).expect("Failed to set RATE_LIMIT_IPV6_PREFIX");
+    MAX_CONNECTIONS_PER_IP.set(max_connections_per_ip).expect("Failed to set MAX_CONNECTIONS_PER_IP");
+    BROADCAST_TICK_MS.set(broadcast_tick_ms).expect("Failed to set BROADCAST_TICK_MS");
+    RESUME_MAX_CHANGES.set(resume_max_changes).expect("Failed to set RESUME_MAX_CHANGES");
+    WAL_SYNC_INTERVAL_MS.set(wal_sync_interval_ms).expect("Failed to set WAL_SYNC_INTERVAL_MS");
+    RETAIN_CHANGES.set(retain_changes).expect("Failed to set RETAIN_CHANGES");
+    RETAIN_DAYS.set(retain_days).expect("Failed to set RETAIN_DAYS");
+    HISTORY_ARCHIVE_DIR.set(history_archive_dir).expect("Failed to set HISTORY_ARCHIVE_DIR");
+    PALETTE_GC_MIN_UNUSED.set(palette_gc_min_unused).expect("Failed to set PALETTE_GC_MIN_UNUSED");
+    PAINT_COOLDOWN_MS.set(paint_cooldown_ms).expect("Failed to set PAINT_COOLDOWN_MS");
+    ADMIN_PAINT_COOLDOWN_MS.set(admin_paint_cooldown_ms).expect("Failed to set ADMIN_PAINT_COOLDOWN_MS");
+    MODERATOR_PAINT_COOLDOWN_MS.set(moderator_paint_cooldown_ms).expect("Failed to set MODERATOR_PAINT_COOLDOWN_MS");
+    MODERATOR_PERMISSIONS.set(moderator_permissions).expect("Failed to set MODERATOR_PERMISSIONS");
+    PLAYER_PERMISSIONS.set(player_permissions).expect("Failed to set PLAYER_PERMISSIONS");
+    VIEWER_PERMISSIONS.set(viewer_permissions).expect("Failed to set VIEWER_PERMISSIONS");
+    MAX_CANVAS_PIXELS.set(max_canvas_pixels).expect("Failed to set MAX_CANVAS_PIXELS");
     ADMIN_TOKEN.set(token).expect("Failed to set ADMIN_TOKEN");
+    SESSION_SECRET.set(session_secret).expect("Failed to set SESSION_SECRET");
     DEFAULT_CANVAS_WIDTH.set(width).expect("Failed to set DEFAULT_CANVAS_WIDTH");
     DEFAULT_CANVAS_HEIGHT.set(height).expect("Failed to set DEFAULT_CANVAS_HEIGHT");
     DEFAULT_SNAPSHOT_INTERVAL.set(snapshot_interval).expect("Failed to set DEFAULT_SNAPSHOT_INTERVAL");
+    SNAPSHOT_KEYFRAME_INTERVAL.set(keyframe_interval).expect("Failed to set SNAPSHOT_KEYFRAME_INTERVAL");
     PERSISTENCE_PATH.set(persistence_path).expect("Failed to set PERSISTENCE_PATH");
+    ROOMS_DIR.set(rooms_dir).expect("Failed to set ROOMS_DIR");
     
     println!("Environment variables loaded");
     println!("Canvas size: {}x{}", width, height);
 }
 
+/// Parse a comma-separated permission list from `var`. A typo would silently
+/// take a permission away, so unknown names stop the server instead.
+fn permissions(var: &str, default: &str) -> Vec<Permission> {
+    let list = std::env::var(var).unwrap_or_else(|_| default.to_string());
+    list.split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| Permission::parse(name).unwrap_or_else(|| panic!("Unknown permission '{}' in {}", name, var)))
+        .collect()
+}
+
 pub fn admin_token() -> &'static str {
     ADMIN_TOKEN.get().expect("Environment not initialized. Call env::init() first")
 }
 
+pub fn session_secret() -> Option<&'static str> {
+    SESSION_SECRET.get().expect("Environment not initialized. Call env::init() first").as_deref()
+}
+
 pub fn default_canvas_width() -> usize {
     *DEFAULT_CANVAS_WIDTH.get().expect("Environment not initialized. Call env::init() first")
 }
@@ -78,10 +280,18 @@ pub fn default_snapshot_interval() -> usize {
     *DEFAULT_SNAPSHOT_INTERVAL.get().expect("Environment not initialized. Call env::init() first")
 }
 
+pub fn snapshot_keyframe_interval() -> usize {
+    *SNAPSHOT_KEYFRAME_INTERVAL.get().expect("Environment not initialized. Call env::init() first")
+}
+
 pub fn persistence_path() -> &'static str {
     PERSISTENCE_PATH.get().expect("Environment not initialized. Call env::init() first")
 }
 
+pub fn rooms_dir() -> &'static str {
+    ROOMS_DIR.get().expect("Environment not initialized. Call env::init() first")
+}
+
 pub fn autosave_interval() -> u64 {
     *AUTOSAVE_INTERVAL.get().expect("Environment not initialized. Call env::init() first")
 }
@@ -93,3 +303,95 @@ pub fn rate_limit_tokens() -> f64 {
 pub fn rate_limit_refill_rate_ms() -> f64 {
     *RATE_LIMIT_REFILL_RATE_MS.get().expect("Environment not initialized. Call env::init() first")
 }
+
+pub fn ping_rate_limit_tokens() -> f64 {
+    *PING_RATE_LIMIT_TOKENS.get().expect("Environment not initialized. Call env::init() first")
+}
+
+pub fn ping_rate_limit_refill_rate_ms() -> f64 {
+    *PING_RATE_LIMIT_REFILL_RATE_MS.get().expect("Environment not initialized. Call env::init() first")
+}
+
+pub fn query_rate_limit_tokens() -> f64 {
+    *QUERY_RATE_LIMIT_TOKENS.get().expect("Environment not initialized. Call env::init() first")
+}
+
+pub fn query_rate_limit_refill_rate_ms() -> f64 {
+    *QUERY_RATE_LIMIT_REFILL_RATE_MS.get().expect("Environment not initialized. Call env::init() first")
+}
+
+pub fn image_rate_limit_tokens() -> f64 {
+    *IMAGE_RATE_LIMIT_TOKENS.get().expect("Environment not initialized. Call env::init() first")
+}
+
+pub fn image_rate_limit_refill_rate_ms() -> f64 {
+    *IMAGE_RATE_LIMIT_REFILL_RATE_MS.get().expect("Environment not initialized. Call env::init() first")
+}
+
+pub fn rate_limit_ip_multiplier() -> f64 {
+    *RATE_LIMIT_IP_MULTIPLIER.get().expect("Environment not initialized. Call env::init() first")
+}
+
+pub fn rate_limit_ipv6_prefix() -> u32 {
+    *RATE_LIMIT_IPV6_PREFIX.get().expect("Environment not initialized. Call env::init() first")
+}
+
+pub fn max_connections_per_ip() -> usize {
+    *MAX_CONNECTIONS_PER_IP.get().expect("Environment not initialized. Call env::init() first")
+}
+
+pub fn broadcast_tick_ms() -> u64 {
+    *BROADCAST_TICK_MS.get().expect("Environment not initialized. Call env::init() first")
+}
+
+pub fn resume_max_changes() -> usize {
+    *RESUME_MAX_CHANGES.get().expect("Environment not initialized. Call env::init() first")
+}
+
+pub fn wal_sync_interval_ms() -> u64 {
+    *WAL_SYNC_INTERVAL_MS.get().expect("Environment not initialized. Call env::init() first")
+}
+
+pub fn retain_changes() -> Option<usize> {
+    *RETAIN_CHANGES.get().expect("Environment not initialized. Call env::init() first")
+}
+
+pub fn retain_days() -> Option<f64> {
+    *RETAIN_DAYS.get().expect("Environment not initialized. Call env::init() first")
+}
+
+pub fn history_archive_dir() -> Option<&'static str> {
+    HISTORY_ARCHIVE_DIR.get().expect("Environment not initialized. Call env::init() first").as_deref()
+}
+
+pub fn palette_gc_min_unused() -> usize {
+    *PALETTE_GC_MIN_UNUSED.get().expect("Environment not initialized. Call env::init() first")
+}
+
+pub fn paint_cooldown_ms() -> u64 {
+    *PAINT_COOLDOWN_MS.get().expect("Environment not initialized. Call env::init() first")
+}
+
+pub fn admin_paint_cooldown_ms() -> u64 {
+    *ADMIN_PAINT_COOLDOWN_MS.get().expect("Environment not initialized. Call env::init() first")
+}
+
+pub fn moderator_paint_cooldown_ms() -> u64 {
+    *MODERATOR_PAINT_COOLDOWN_MS.get().expect("Environment not initialized. Call env::init() first")
+}
+
+pub fn moderator_permissions() -> &'static [Permission] {
+    MODERATOR_PERMISSIONS.get().expect("Environment not initialized. Call env::init() first")
+}
+
+pub fn player_permissions() -> &'static [Permission] {
+    PLAYER_PERMISSIONS.get().expect("Environment not initialized. Call env::init() first")
+}
+
+pub fn viewer_permissions() -> &'static [Permission] {
+    VIEWER_PERMISSIONS.get().expect("Environment not initialized. Call env::init() first")
+}
+
+pub fn max_canvas_pixels() -> usize {
+    *MAX_CANVAS_PIXELS.get().expect("Environment not initialized. Call env::init() first")
+}
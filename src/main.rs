@@ -1,16 +1,47 @@
 mod server;
 mod world;
 mod env;
+mod timelapse;
+mod bench;
 
 use server::Server;
 
 #[tokio::main]
 async fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("timelapse") {
+        if let Err(e) = timelapse::run(&args[2..]) {
+            eprintln!("Timelapse error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if args.get(1).map(String::as_str) == Some("issue-token") {
+        if let Err(e) = server::auth::run(&args[2..]) {
+            eprintln!("Token error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if args.get(1).map(String::as_str) == Some("bench-snapshots") {
+        if let Err(e) = bench::run(&args[2..]) {
+            eprintln!("Benchmark error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     println!("Starting Pixie WebSocket server...");
         // Initialize environment variables
     env::init();
-        let server = Server::new("127.0.0.1:8080");
-    
+        let server = match Server::new("127.0.0.1:8080") {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("Failed to start server: {}", e);
+            std::process::exit(1);
+        }
+    };
+
     if let Err(e) = server.run().await {
         eprintln!("Server error: {}", e);
     }
@@ -0,0 +1,138 @@
+//! Session tokens issued by this server and signed with `SESSION_SECRET`.
+//!
+//! A token is `<payload>.<signature>`: the claims as base64url encoded JSON, then
+//! the base64url encoded HMAC-SHA256 of the payload part.
+
+use super::messages::Role;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const USAGE: &str = "\
+Usage: Pixie issue-token --user <id> --name <display name> [options]
+
+Prints a session token signed with SESSION_SECRET. Clients pass it as the
+`token` query parameter or an `Authorization: Bearer` header.
+
+Options:
+  --role <role>              admin, moderator, player or viewer (default player)
+  --days <N>                 days until the token expires (default: never)";
+
+/// Who a token was issued to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    /// Stable user id; changes are attributed to `user:<id>`
+    #[serde(rename = "sub")]
+    pub user_id: String,
+    pub name: String,
+    pub role: Role,
+    /// Unix seconds after which the token is rejected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exp: Option<u64>,
+}
+
+impl Claims {
+    /// The identity changes, cooldowns and rate limits are keyed by
+    pub fn identity(&self) -> String {
+        format!("user:{}", self.user_id)
+    }
+}
+
+/// Sign `claims` into a token
+pub fn issue(claims: &Claims, secret: &str) -> Result<String, String> {
+    let payload = serde_json::to_vec(claims).map_err(|e| e.to_string())?;
+    let payload = URL_SAFE_NO_PAD.encode(payload);
+    let signature = URL_SAFE_NO_PAD.encode(mac(secret, &payload).finalize().into_bytes());
+    Ok(format!("{}.{}", payload, signature))
+}
+
+/// Check a token's signature and expiry and return its claims
+pub fn verify(token: &str, secret: &str) -> Result<Claims, String> {
+    let (payload, signature) = token.split_once('.').ok_or("Malformed session token")?;
+    let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| "Malformed session token")?;
+    mac(secret, payload)
+        .verify_slice(&signature)
+        .map_err(|_| "Invalid session token signature")?;
+
+    let payload = URL_SAFE_NO_PAD.decode(payload).map_err(|_| "Malformed session token")?;
+    let claims: Claims = serde_json::from_slice(&payload).map_err(|e| format!("Malformed session token claims: {}", e))?;
+    if !is_valid_user_id(&claims.user_id) {
+        return Err("Session token has an invalid user id".to_string());
+    }
+    if claims.exp.is_some_and(|exp| exp <= now_secs()) {
+        return Err("Session token has expired".to_string());
+    }
+    Ok(claims)
+}
+
+fn mac(secret: &str, payload: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(payload.as_bytes());
+    mac
+}
+
+/// User ids end up in identities and logs, so keep them to a conservative character set
+fn is_valid_user_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 64 && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Entry point for `Pixie issue-token ...`; `args` excludes the subcommand itself
+pub fn run(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    dotenvy::dotenv().ok();
+    let secret = std::env::var("SESSION_SECRET")
+        .ok()
+        .filter(|secret| !secret.is_empty())
+        .ok_or("SESSION_SECRET must be set to issue tokens")?;
+    let claims = parse_args(args).map_err(|e| format!("{}\n\n{}", e, USAGE))?;
+    println!("{}", issue(&claims, &secret)?);
+    Ok(())
+}
+
+fn parse_args(args: &[String]) -> Result<Claims, String> {
+    let (mut user_id, mut name, mut role, mut days) = (None, None, Role::Player, None);
+
+    let mut rest = args.iter();
+    while let Some(flag) = rest.next() {
+        let value = rest.next().ok_or_else(|| format!("Missing value for {}", flag))?;
+        match flag.as_str() {
+            "--user" => user_id = Some(value.clone()),
+            "--name" => name = Some(value.clone()),
+            "--role" => {
+                role = serde_json::from_value(serde_json::Value::String(value.clone()))
+                    .map_err(|_| format!("Unknown role: {}", value))?;
+            }
+            "--days" => {
+                let parsed: f64 = value
+                    .parse()
+                    .ok()
+                    .filter(|&days: &f64| days > 0.0)
+                    .ok_or_else(|| format!("Invalid value for --days: {}", value))?;
+                days = Some(parsed);
+            }
+            _ => return Err(format!("Unknown option {}", flag)),
+        }
+    }
+
+    let user_id = user_id.ok_or("Missing --user")?;
+    if !is_valid_user_id(&user_id) {
+        return Err("User ids must be 1-64 characters of letters, digits, '-' or '_'".to_string());
+    }
+    Ok(Claims {
+        user_id,
+        name: name.ok_or("Missing --name")?,
+        role,
+        exp: days.map(|days| now_secs() + (days * 24.0 * 60.0 * 60.0) as u64),
+    })
+}
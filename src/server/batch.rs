@@ -0,0 +1,34 @@
+use super::messages::PixelUpdate;
+use std::collections::HashMap;
+
+/// Paints applied since the last broadcast tick, deduplicated per pixel
+#[derive(Default)]
+pub struct PendingUpdates {
+    pixels: HashMap<(usize, usize), (usize, String)>,
+}
+
+impl PendingUpdates {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a paint at `change_index`; later writes to the same pixel replace earlier ones
+    pub fn push(&mut self, change_index: usize, x: usize, y: usize, color: String) {
+        self.pixels.insert((x, y), (change_index, color));
+    }
+
+    /// Discard everything pending, e.g. when a full `Init` supersedes it
+    pub fn clear(&mut self) {
+        self.pixels.clear();
+    }
+
+    /// Take all pending updates, ordered by the history index of their last write
+    pub fn drain(&mut self) -> Vec<PixelUpdate> {
+        let mut pending: Vec<_> = self.pixels.drain().collect();
+        pending.sort_unstable_by_key(|(_, (change_index, _))| *change_index);
+        pending
+            .into_iter()
+            .map(|((x, y), (_, color))| PixelUpdate { x, y, color })
+            .collect()
+    }
+}
@@ -0,0 +1,383 @@
+//! Compact binary encoding of the client/server protocol.
+//!
+//! All integers are little endian. Every frame starts with a one byte opcode.
+//! Request ids are encoded as a `u64` where `0` means "no id".
+//!
+//! Client → server:
+//! - `0x01` paint: id `u64`, x `u32`, y `u32`, palette index `u32`; the index refers to
+//!   the restricted palette if the canvas has one, otherwise to the board palette
+//! - `0x02` ping: id `u64`
+//! - `0x03` paint by color: id `u64`, x `u32`, y `u32`, RGBA quadruple; checked
+//!   against the restricted palette like a JSON paint by `color`
+//!
+//! Server → client:
+//! - `0x01` init: width `u32`, height `u32`, cooldown `u64`, seq `u64`, palette length `u32`,
+//!   palette as RGB triples, index width `u8` (1, 2 or 4), the board indices, then
+//!   restricted palette length `u32` (0 if unrestricted) and its colors as RGB triples
+//! - `0x02` updates: seq `u64`, count `u32`, then per pixel x `u32`, y `u32`, RGB triple
+//! - `0x03` pong: clients `u32`
+//! - `0x04` ack: id `u64`
+//! - `0x05` board palette: palette length `u32`, palette as RGB triples; replaces the
+//!   palette from `init`, keeping every color still on the board
+//! - `0x06` cooldown: milliseconds until the next allowed paint `u64`
+//!
+//! Server messages without a binary encoding are sent as JSON text frames.
+
+use super::error::RequestError;
+use super::messages::{ClientEnvelope, ClientMessage, ErrorCode, ServerMessage};
+use crate::world::color::Color;
+use crate::world::palette::Palette;
+
+const CLIENT_PAINT: u8 = 0x01;
+const CLIENT_PING: u8 = 0x02;
+const CLIENT_PAINT_RGBA: u8 = 0x03;
+
+const SERVER_INIT: u8 = 0x01;
+const SERVER_UPDATES: u8 = 0x02;
+const SERVER_PONG: u8 = 0x03;
+const SERVER_ACK: u8 = 0x04;
+const SERVER_BOARD_PALETTE: u8 = 0x05;
+const SERVER_COOLDOWN: u8 = 0x06;
+
+/// Wire format negotiated for a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    Json,
+    Binary,
+}
+
+impl Protocol {
+    /// Pick the protocol from the `protocol` handshake query parameter
+    pub fn from_query(value: Option<&String>) -> Self {
+        match value.map(|s| s.as_str()) {
+            Some("binary") => Protocol::Binary,
+            _ => Protocol::Json,
+        }
+    }
+}
+
+/// Encode a server message, returning `None` if it has no binary form
+pub fn encode_server_message(msg: &ServerMessage) -> Option<Vec<u8>> {
+    let mut buf = Vec::new();
+    match msg {
+        ServerMessage::Init { width, height, palette, board, allowed_palette, cooldown, seq } => {
+            buf.push(SERVER_INIT);
+            buf.extend_from_slice(&(*width as u32).to_le_bytes());
+            buf.extend_from_slice(&(*height as u32).to_le_bytes());
+            buf.extend_from_slice(&cooldown.to_le_bytes());
+            buf.extend_from_slice(&(*seq as u64).to_le_bytes());
+            buf.extend_from_slice(&(palette.len() as u32).to_le_bytes());
+            for hex in palette {
+                let rgb = Color::from_hex(hex).ok()?.rgb();
+                buf.extend_from_slice(&rgb);
+            }
+
+            let index_width: u8 = match palette.len() {
+                0..=0x100 => 1,
+                0x101..=0x10000 => 2,
+                _ => 4,
+            };
+            buf.push(index_width);
+            buf.reserve(board.len() * index_width as usize);
+            for &index in board {
+                match index_width {
+                    1 => buf.push(index as u8),
+                    2 => buf.extend_from_slice(&(index as u16).to_le_bytes()),
+                    _ => buf.extend_from_slice(&index.to_le_bytes()),
+                }
+            }
+
+            let allowed = allowed_palette.as_deref().unwrap_or_default();
+            buf.extend_from_slice(&(allowed.len() as u32).to_le_bytes());
+            for hex in allowed {
+                buf.extend_from_slice(&Color::from_hex(hex).ok()?.rgb());
+            }
+        }
+        ServerMessage::Updates { seq, updates } => {
+            buf.push(SERVER_UPDATES);
+            buf.extend_from_slice(&(*seq as u64).to_le_bytes());
+            buf.extend_from_slice(&(updates.len() as u32).to_le_bytes());
+            buf.reserve(updates.len() * 11);
+            for update in updates {
+                buf.extend_from_slice(&(update.x as u32).to_le_bytes());
+                buf.extend_from_slice(&(update.y as u32).to_le_bytes());
+                buf.extend_from_slice(&Color::from_hex(&update.color).ok()?.rgb());
+            }
+        }
+        ServerMessage::BoardPalette { palette } => {
+            buf.push(SERVER_BOARD_PALETTE);
+            buf.extend_from_slice(&(palette.len() as u32).to_le_bytes());
+            for hex in palette {
+                buf.extend_from_slice(&Color::from_hex(hex).ok()?.rgb());
+            }
+        }
+        ServerMessage::Cooldown { cooldown } => {
+            buf.push(SERVER_COOLDOWN);
+            buf.extend_from_slice(&cooldown.to_le_bytes());
+        }
+        ServerMessage::Pong { clients } => {
+            buf.push(SERVER_PONG);
+            buf.extend_from_slice(&(*clients as u32).to_le_bytes());
+        }
+        ServerMessage::Ack { id } => {
+            buf.push(SERVER_ACK);
+            buf.extend_from_slice(&id.unwrap_or(0).to_le_bytes());
+        }
+        ServerMessage::PixelInfo { .. } | ServerMessage::Palette { .. } | ServerMessage::Error { .. } => return None,
+    }
+    Some(buf)
+}
+
+/// Decode a binary client frame, resolving palette indices against `palette`
+/// unless the canvas is `restricted`, in which case they index the restricted palette
+pub fn decode_client_message(data: &[u8], palette: &Palette, restricted: bool) -> Result<ClientEnvelope, RequestError> {
+    let mut reader = Reader { data };
+    let opcode = reader.u8()?;
+    let id = match reader.u64()? {
+        0 => None,
+        id => Some(id),
+    };
+
+    let message = match opcode {
+        CLIENT_PAINT => {
+            let x = reader.u32()? as usize;
+            let y = reader.u32()? as usize;
+            let index = reader.u32()?;
+            if restricted {
+                ClientMessage::Paint { x, y, color: None, index: Some(index as usize) }
+            } else {
+                let color = palette.get_color(index).ok_or_else(|| {
+                    RequestError::new(ErrorCode::InvalidColor, format!("Unknown palette index {}", index))
+                })?;
+                ClientMessage::Paint { x, y, color: Some(color.to_hex()), index: None }
+            }
+        }
+        CLIENT_PING => ClientMessage::Ping,
+        CLIENT_PAINT_RGBA => {
+            let x = reader.u32()? as usize;
+            let y = reader.u32()? as usize;
+            let color = Color::from_rgba(reader.take()?);
+            ClientMessage::Paint { x, y, color: Some(color.to_hex()), index: None }
+        }
+        other => {
+            return Err(RequestError::new(
+                ErrorCode::InvalidMessage,
+                format!("Unknown binary opcode 0x{:02X}", other),
+            ));
+        }
+    };
+
+    if !reader.data.is_empty() {
+        return Err(RequestError::new(ErrorCode::InvalidMessage, "Trailing bytes in binary frame"));
+    }
+
+    Ok(ClientEnvelope { id, message })
+}
+
+/// Minimal little endian cursor over a binary frame
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], RequestError> {
+        if self.data.len() < N {
+            return Err(RequestError::new(ErrorCode::InvalidMessage, "Truncated binary frame"));
+        }
+        let (head, rest) = self.data.split_at(N);
+        self.data = rest;
+        Ok(head.try_into().expect("split_at returned N bytes"))
+    }
+
+    fn u8(&mut self) -> Result<u8, RequestError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, RequestError> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, RequestError> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::messages::PixelUpdate;
+
+    /// Build a frame of an opcode, a `u64` and `u32` fields, as client frames are laid out
+    fn frame(opcode: u8, id: u64, fields: &[u32]) -> Vec<u8> {
+        let mut frame = vec![opcode];
+        frame.extend_from_slice(&id.to_le_bytes());
+        for field in fields {
+            frame.extend_from_slice(&field.to_le_bytes());
+        }
+        frame
+    }
+
+    fn rgb_list(reader: &mut Reader) -> Vec<String> {
+        let len = reader.u32().unwrap();
+        (0..len)
+            .map(|_| {
+                let [r, g, b] = [reader.u8().unwrap(), reader.u8().unwrap(), reader.u8().unwrap()];
+                Color::from_rgb([r, g, b]).to_hex()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn init_round_trips() {
+        let palette: Vec<String> = ["#FFFFFF", "#FF0000", "#00FF00"].map(String::from).to_vec();
+        let board = vec![0, 1, 2, 1, 0, 2];
+        let allowed: Vec<String> = ["#FF0000", "#00FF00"].map(String::from).to_vec();
+        let message = ServerMessage::Init {
+            width: 3,
+            height: 2,
+            palette: palette.clone(),
+            board: board.clone(),
+            allowed_palette: Some(allowed.clone()),
+            cooldown: 1500,
+            seq: 42,
+        };
+
+        let encoded = encode_server_message(&message).unwrap();
+        let mut reader = Reader { data: &encoded };
+        assert_eq!(reader.u8().unwrap(), SERVER_INIT);
+        assert_eq!((reader.u32().unwrap(), reader.u32().unwrap()), (3, 2));
+        assert_eq!(reader.u64().unwrap(), 1500);
+        assert_eq!(reader.u64().unwrap(), 42);
+        assert_eq!(rgb_list(&mut reader), palette);
+        assert_eq!(reader.u8().unwrap(), 1);
+        let decoded: Vec<u32> = (0..board.len()).map(|_| reader.u8().unwrap() as u32).collect();
+        assert_eq!(decoded, board);
+        assert_eq!(rgb_list(&mut reader), allowed);
+        assert!(reader.data.is_empty());
+    }
+
+    #[test]
+    fn init_widens_indices_for_large_palettes() {
+        let palette: Vec<String> = (0..300u32).map(|i| Color::from_rgb([(i >> 8) as u8, i as u8, 0]).to_hex()).collect();
+        let message = ServerMessage::Init {
+            width: 2,
+            height: 1,
+            palette,
+            board: vec![299, 7],
+            allowed_palette: None,
+            cooldown: 0,
+            seq: 0,
+        };
+
+        let encoded = encode_server_message(&message).unwrap();
+        let mut reader = Reader { data: &encoded };
+        // Opcode, width, height, cooldown and seq
+        reader.take::<25>().unwrap();
+        assert_eq!(rgb_list(&mut reader).len(), 300);
+        assert_eq!(reader.u8().unwrap(), 2);
+        let board: Vec<u16> = (0..2).map(|_| u16::from_le_bytes(reader.take().unwrap())).collect();
+        assert_eq!(board, [299, 7]);
+        assert_eq!(reader.u32().unwrap(), 0);
+        assert!(reader.data.is_empty());
+    }
+
+    #[test]
+    fn updates_round_trip() {
+        let updates = vec![
+            PixelUpdate { x: 1, y: 2, color: "#123456".to_string() },
+            PixelUpdate { x: 70000, y: 3, color: "#ABCDEF".to_string() },
+        ];
+        let encoded = encode_server_message(&ServerMessage::Updates { seq: 9, updates: updates.clone() }).unwrap();
+
+        let mut reader = Reader { data: &encoded };
+        assert_eq!(reader.u8().unwrap(), SERVER_UPDATES);
+        assert_eq!(reader.u64().unwrap(), 9);
+        assert_eq!(reader.u32().unwrap(), 2);
+        for update in &updates {
+            assert_eq!(reader.u32().unwrap() as usize, update.x);
+            assert_eq!(reader.u32().unwrap() as usize, update.y);
+            let rgb: [u8; 3] = reader.take().unwrap();
+            assert_eq!(Color::from_rgb(rgb).to_hex(), update.color);
+        }
+        assert!(reader.data.is_empty());
+    }
+
+    #[test]
+    fn small_messages_encode_their_fields() {
+        assert_eq!(encode_server_message(&ServerMessage::Ack { id: Some(7) }).unwrap(), frame(SERVER_ACK, 7, &[]));
+        assert_eq!(encode_server_message(&ServerMessage::Ack { id: None }).unwrap(), frame(SERVER_ACK, 0, &[]));
+        assert_eq!(encode_server_message(&ServerMessage::Pong { clients: 3 }).unwrap(), [SERVER_PONG, 3, 0, 0, 0]);
+        assert_eq!(
+            encode_server_message(&ServerMessage::Cooldown { cooldown: 5000 }).unwrap(),
+            frame(SERVER_COOLDOWN, 5000, &[])
+        );
+        let palette = ServerMessage::BoardPalette { palette: vec!["#FFFFFF".to_string(), "#000000".to_string()] };
+        assert_eq!(
+            encode_server_message(&palette).unwrap(),
+            [SERVER_BOARD_PALETTE, 2, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0, 0, 0]
+        );
+        assert!(encode_server_message(&ServerMessage::Palette { colors: None }).is_none());
+    }
+
+    #[test]
+    fn paint_resolves_board_palette_indices() {
+        let mut palette = Palette::new();
+        let red = palette.add_color(Color::from_hex("#FF0000").unwrap());
+
+        let envelope = decode_client_message(&frame(CLIENT_PAINT, 5, &[10, 20, red]), &palette, false).unwrap();
+        assert_eq!(envelope.id, Some(5));
+        let ClientMessage::Paint { x, y, color, index } = envelope.message else {
+            panic!("Expected a paint");
+        };
+        assert_eq!((x, y, color.as_deref(), index), (10, 20, Some("#FF0000"), None));
+
+        let error = decode_client_message(&frame(CLIENT_PAINT, 0, &[0, 0, 99]), &palette, false).unwrap_err();
+        assert_eq!(error.code, ErrorCode::InvalidColor);
+    }
+
+    #[test]
+    fn paint_keeps_restricted_palette_indices() {
+        let envelope = decode_client_message(&frame(CLIENT_PAINT, 0, &[1, 2, 3]), &Palette::new(), true).unwrap();
+        assert_eq!(envelope.id, None);
+        let ClientMessage::Paint { color, index, .. } = envelope.message else {
+            panic!("Expected a paint");
+        };
+        assert_eq!((color, index), (None, Some(3)));
+    }
+
+    #[test]
+    fn paint_by_color_carries_rgba() {
+        let mut frame = frame(CLIENT_PAINT_RGBA, 2, &[3, 4]);
+        frame.extend_from_slice(&[0x12, 0x34, 0x56, 0x80]);
+        for restricted in [false, true] {
+            let envelope = decode_client_message(&frame, &Palette::new(), restricted).unwrap();
+            assert_eq!(envelope.id, Some(2));
+            let ClientMessage::Paint { x, y, color, index } = envelope.message else {
+                panic!("Expected a paint");
+            };
+            assert_eq!((x, y, color.as_deref(), index), (3, 4, Some("#12345680"), None));
+        }
+
+        frame.pop();
+        let error = decode_client_message(&frame, &Palette::new(), false).unwrap_err();
+        assert_eq!(error.code, ErrorCode::InvalidMessage);
+    }
+
+    #[test]
+    fn malformed_frames_are_rejected() {
+        let palette = Palette::new();
+        assert!(matches!(
+            decode_client_message(&frame(CLIENT_PING, 1, &[]), &palette, false).unwrap().message,
+            ClientMessage::Ping
+        ));
+
+        let mut truncated = frame(CLIENT_PAINT, 1, &[0, 0, 0]);
+        truncated.pop();
+        let mut trailing = frame(CLIENT_PING, 1, &[]);
+        trailing.push(0);
+        for frame in [truncated, trailing, frame(0x7F, 1, &[]), Vec::new()] {
+            let error = decode_client_message(&frame, &palette, false).unwrap_err();
+            assert_eq!(error.code, ErrorCode::InvalidMessage);
+        }
+    }
+}
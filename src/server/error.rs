@@ -0,0 +1,71 @@
+use super::messages::{ErrorCode, ServerMessage};
+use crate::world::canvas::CanvasError;
+use crate::world::WorldError;
+use crate::world::history::RollbackError;
+
+/// A rejected client request, carrying a stable code and a human readable reason
+#[derive(Debug)]
+pub struct RequestError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl RequestError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// Convert into the error message sent back to the client
+    pub fn into_message(self, id: Option<u64>) -> ServerMessage {
+        ServerMessage::Error {
+            id,
+            code: self.code,
+            message: self.message,
+        }
+    }
+}
+
+impl From<CanvasError> for RequestError {
+    fn from(error: CanvasError) -> Self {
+        match error {
+            CanvasError::OutOfBounds { width, height } => Self::new(
+                ErrorCode::OutOfBounds,
+                format!("Coordinates are outside the {}x{} canvas", width, height),
+            ),
+            CanvasError::InvalidDimensions { width, height } => Self::new(
+                ErrorCode::InvalidDimensions,
+                format!("Invalid canvas dimensions {}x{}", width, height),
+            ),
+        }
+    }
+}
+
+impl From<RollbackError> for RequestError {
+    fn from(error: RollbackError) -> Self {
+        match error {
+            RollbackError::IndexOutOfBounds { target, max } => Self::new(
+                ErrorCode::RollbackOutOfRange,
+                format!("Rollback target {} is out of range (max {})", target, max),
+            ),
+            RollbackError::Pruned { target, earliest } => Self::new(
+                ErrorCode::HistoryPruned,
+                format!("Change {} is no longer retained; the earliest rollback target is {}", target, earliest),
+            ),
+            RollbackError::NothingToUndo => Self::new(ErrorCode::NothingToUndo, "There is no rollback to undo"),
+            RollbackError::NothingToRedo => Self::new(ErrorCode::NothingToRedo, "There is no undone rollback to redo"),
+        }
+    }
+}
+
+impl From<WorldError> for RequestError {
+    fn from(error: WorldError) -> Self {
+        match error {
+            WorldError::Canvas(error) => error.into(),
+            WorldError::Rollback(error) => error.into(),
+            WorldError::Log => Self::new(ErrorCode::Internal, "Failed to record the change; it was not applied"),
+        }
+    }
+}
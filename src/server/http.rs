@@ -0,0 +1,202 @@
+use super::Server;
+use super::rate_limit::{ConnectionSlot, MessageKind, RateLimits};
+use super::room::RoomRegistry;
+use crate::world::history::Reconstruction;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Upper bound on the request head we are willing to buffer
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+/// What a new connection asks for, judging by its request line
+pub enum RequestKind {
+    Image,
+    WebSocket,
+    /// The request line doesn't fit in `MAX_REQUEST_HEAD`
+    TooLong,
+}
+
+/// Peek at the request line to tell plain HTTP image requests apart from
+/// WebSocket handshakes, without consuming anything from the stream
+pub async fn request_kind(stream: &TcpStream) -> RequestKind {
+    let mut buf = vec![0u8; MAX_REQUEST_HEAD];
+    // Give slow clients a moment to send the request line
+    for _ in 0..50 {
+        let n = match stream.peek(&mut buf).await {
+            Ok(0) | Err(_) => return RequestKind::WebSocket,
+            Ok(n) => n,
+        };
+        if let Some(end) = buf[..n].windows(2).position(|w| w == b"\r\n") {
+            let image = parse_request_line(&buf[..end])
+                .is_some_and(|(method, path, _)| method == "GET" && image_room_path(&path).is_some());
+            return if image { RequestKind::Image } else { RequestKind::WebSocket };
+        }
+        if n == buf.len() {
+            return RequestKind::TooLong;
+        }
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+    }
+    RequestKind::WebSocket
+}
+
+/// Answer with an error status and close the connection. The response goes out
+/// without reading the request, so this suits requests that aren't worth reading.
+pub async fn refuse(mut stream: TcpStream, status: &str, reason: &str) {
+    stream.write_all(&text_response(status, reason)).await.ok();
+    stream.shutdown().await.ok();
+}
+
+/// Answer `GET [/room]/canvas.png[?at=<change index>|?time=<unix ms>][&room=<name>]` with a PNG of the canvas.
+/// The connection counts against its address group through `_slot` until answered.
+pub async fn serve_image(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    _slot: ConnectionSlot,
+    rooms: &RoomRegistry,
+    rate_limits: &Arc<RateLimits>,
+) {
+    let head = match read_request_head(&mut stream).await {
+        Ok(head) => head,
+        Err(e) => {
+            eprintln!("Failed to read HTTP request from {}: {}", addr, e);
+            return;
+        }
+    };
+
+    let response = match parse_request_line(head.split(|&b| b == b'\n').next().unwrap_or_default()) {
+        Some(_) if !rate_limits.take(MessageKind::Image, addr.ip(), None) => {
+            text_response("429 Too Many Requests", "Rate limit exceeded for image requests")
+        }
+        Some((_, path, query)) => render(rooms, &path, &parse_query(&query)).await,
+        None => text_response("400 Bad Request", "Malformed request"),
+    };
+
+    if let Err(e) = stream.write_all(&response).await {
+        eprintln!("Failed to write HTTP response to {}: {}", addr, e);
+    }
+    stream.shutdown().await.ok();
+}
+
+async fn render(rooms: &RoomRegistry, path: &str, query: &HashMap<String, String>) -> Vec<u8> {
+    let room_name = match query.get("room") {
+        Some(name) => name.clone(),
+        None => Server::room_from_path(image_room_path(path).unwrap_or_default()),
+    };
+    let Some(room) = rooms.get(&room_name).await else {
+        return text_response("404 Not Found", &format!("Room '{}' does not exist", room_name));
+    };
+
+    // Only copy what's needed under the lock; replaying history can take a while
+    let reconstruction = {
+        let world_lock = room.world.read().await;
+        match (query.get("at"), query.get("time")) {
+            (None, None) => Reconstruction::from(world_lock.canvas.clone()),
+            (Some(_), Some(_)) => return text_response("400 Bad Request", "Use either 'at' or 'time', not both"),
+            // The canvas as it was at a Unix timestamp in milliseconds
+            (None, Some(time)) => match time.parse::<u64>() {
+                Ok(time) => match world_lock.history.change_count_at_time(time) {
+                    Some(count) => world_lock.history.reconstruction_at(count),
+                    None => return text_response("410 Gone", "That time is before the retained history"),
+                },
+                Err(_) => return text_response("400 Bad Request", "'time' must be a Unix timestamp in milliseconds"),
+            },
+            (Some(at), None) => match at.parse::<usize>() {
+                // The canvas as it was right after change `at`
+                Ok(at) if at < world_lock.change_count() => {
+                    if !world_lock.history.is_reconstructible(at + 1) {
+                        return text_response(
+                            "410 Gone",
+                            &format!(
+                                "Change {} is no longer retained; history starts at change {}",
+                                at,
+                                world_lock.history.first_retained_index()
+                            ),
+                        );
+                    }
+                    world_lock.history.reconstruction_at(at + 1)
+                }
+                _ => {
+                    return text_response(
+                        "400 Bad Request",
+                        &format!("'at' must be a change index below {}", world_lock.change_count()),
+                    );
+                }
+            },
+        }
+    };
+    let canvas = reconstruction.into_canvas();
+
+    match crate::world::image::encode_png(&canvas) {
+        Ok(png) => {
+            let mut response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: image/png\r\nContent-Length: {}\r\nCache-Control: no-cache\r\nAccess-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n",
+                png.len()
+            )
+            .into_bytes();
+            response.extend_from_slice(&png);
+            response
+        }
+        Err(e) => {
+            eprintln!("Failed to encode canvas of room '{}' as PNG: {}", room_name, e);
+            text_response("500 Internal Server Error", "Failed to encode image")
+        }
+    }
+}
+
+/// Strip the `/canvas.png` suffix, leaving the room part of the path
+fn image_room_path(path: &str) -> Option<&str> {
+    path.strip_suffix("canvas.png")
+        .filter(|prefix| prefix.ends_with('/'))
+}
+
+/// Split `GET /path?query HTTP/1.1` into method, path and query string
+fn parse_request_line(line: &[u8]) -> Option<(String, String, String)> {
+    let line = std::str::from_utf8(line).ok()?.trim_end();
+    let mut parts = line.split(' ');
+    let method = parts.next()?;
+    let target = parts.next()?;
+    parts.next().filter(|version| version.starts_with("HTTP/"))?;
+
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    Some((method.to_string(), path.to_string(), query.to_string()))
+}
+
+/// Parse a `key=value&...` query string, decoding values
+pub fn parse_query(query: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    for param in query.split('&') {
+        if let Some((key, value)) = param.split_once('=') {
+            params.insert(
+                key.to_string(),
+                urlencoding::decode(value).unwrap_or_default().to_string()
+            );
+        }
+    }
+    params
+}
+
+async fn read_request_head(stream: &mut TcpStream) -> std::io::Result<Vec<u8>> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || head.len() + n > MAX_REQUEST_HEAD {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "incomplete or oversized request head"));
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    Ok(head)
+}
+
+fn text_response(status: &str, body: &str) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
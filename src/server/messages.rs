@@ -1,12 +1,38 @@
 use serde::{Deserialize, Serialize};
-use crate::world::change::ResizeAnchor;
+use std::collections::HashMap;
+use crate::world::change::{Region, ResizeAnchor};
+
+/// What a connection is allowed to do, decided when it connects
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Admin,
+    Moderator,
+    Player,
+    /// Can watch but, by default, not paint
+    Viewer,
+}
+
+impl Role {
+    /// Name used in messages and persisted room settings
+    pub fn name(self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Moderator => "moderator",
+            Role::Player => "player",
+            Role::Viewer => "viewer",
+        }
+    }
+}
 
 /// Messages sent from client to server
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum ClientMessage {
+    /// Paint with either a `color` string or, on a canvas with a restricted
+    /// palette, the `index` of an allowed color
     #[serde(rename = "paint")]
-    Paint { x: usize, y: usize, color: String },
+    Paint { x: usize, y: usize, color: Option<String>, index: Option<usize> },
     
     #[serde(rename = "ping")]
     Ping,
@@ -16,6 +42,119 @@ pub enum ClientMessage {
     
     #[serde(rename = "rollback")]
     Rollback { target_index: usize },
+
+    #[serde(rename = "undo_rollback")]
+    UndoRollback,
+
+    #[serde(rename = "redo_rollback")]
+    RedoRollback,
+
+    /// Stamp a base64 encoded image onto the canvas with its top-left corner at (x, y).
+    /// `width` and `height` are required for raw RGB data.
+    #[serde(rename = "import")]
+    Import {
+        x: usize,
+        y: usize,
+        format: ImageFormat,
+        data: String,
+        width: Option<usize>,
+        height: Option<usize>,
+    },
+
+    /// Revert only the paints matching every given criterion
+    #[serde(rename = "revert_paints")]
+    RevertPaints {
+        author: Option<String>,
+        region: Option<Region>,
+        from: Option<u64>,
+        to: Option<u64>,
+    },
+
+    #[serde(rename = "pixel_info")]
+    PixelInfo { x: usize, y: usize },
+
+    /// Restrict paints to `colors`, or allow any color again with `null`
+    #[serde(rename = "set_palette")]
+    SetPalette { colors: Option<Vec<String>> },
+
+    /// Set the milliseconds `role` must wait between paints in this room,
+    /// or fall back to the server default with `null`
+    #[serde(rename = "set_cooldown")]
+    SetCooldown { role: Role, cooldown: Option<u64> },
+
+    /// Stop `identity` from changing this room for `duration_ms`, or for good
+    /// if it is left out
+    #[serde(rename = "ban")]
+    Ban { identity: String, duration_ms: Option<u64> },
+
+    #[serde(rename = "unban")]
+    Unban { identity: String },
+
+    #[serde(rename = "create_room")]
+    CreateRoom {
+        name: String,
+        width: Option<usize>,
+        height: Option<usize>,
+        palette: Option<Vec<String>>,
+        cooldowns: Option<HashMap<Role, u64>>,
+    },
+
+    #[serde(rename = "archive_room")]
+    ArchiveRoom { name: String },
+
+    #[serde(rename = "delete_room")]
+    DeleteRoom { name: String },
+}
+
+/// Encodings accepted by the `import` message
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    Png,
+    Rgb,
+}
+
+/// A client message together with the optional request id echoed back in the reply
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClientEnvelope {
+    #[serde(default)]
+    pub id: Option<u64>,
+    #[serde(flatten)]
+    pub message: ClientMessage,
+}
+
+/// Stable, machine-readable reasons a client message was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidMessage,
+    RateLimited,
+    Cooldown,
+    InvalidColor,
+    InvalidPalette,
+    OutOfBounds,
+    InvalidDimensions,
+    Forbidden,
+    Banned,
+    RollbackOutOfRange,
+    HistoryPruned,
+    NothingToUndo,
+    NothingToRedo,
+    InvalidFilter,
+    InvalidImage,
+    RoomNotFound,
+    RoomExists,
+    RoomArchived,
+    InvalidRoomName,
+    Internal,
+}
+
+/// A single pixel change inside a batched `updates` message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PixelUpdate {
+    pub x: usize,
+    pub y: usize,
+    pub color: String,
 }
 
 /// Messages sent from server to client
@@ -28,18 +167,64 @@ pub enum ServerMessage {
         height: usize,
         palette: Vec<String>,
         board: Vec<u32>,
+        /// Colors paints are restricted to, referenced by index; `None` allows any color
+        allowed_palette: Option<Vec<String>>,
+        /// Milliseconds until this client may paint again
         cooldown: u64,
+        /// Number of history changes reflected in this board
+        seq: usize,
     },
     
-    #[serde(rename = "update")]
-    Update {
-        x: usize,
-        y: usize,
-        color: String,
+    #[serde(rename = "updates")]
+    Updates {
+        /// Number of history changes reflected once these updates are applied
+        seq: usize,
+        updates: Vec<PixelUpdate>,
     },
     
+    /// The canvas's restricted palette changed
+    #[serde(rename = "palette")]
+    Palette {
+        colors: Option<Vec<String>>,
+    },
+
+    /// Unused colors were dropped from the board palette, renumbering it.
+    /// Board indices from earlier messages must be translated through their colors.
+    #[serde(rename = "board_palette")]
+    BoardPalette {
+        palette: Vec<String>,
+    },
+
+    /// Milliseconds until this client may paint again, sent after each paint
+    /// in rooms with a cooldown
+    #[serde(rename = "cooldown")]
+    Cooldown {
+        cooldown: u64,
+    },
+
     #[serde(rename = "pong")]
     Pong {
         clients: usize,
     },
+
+    #[serde(rename = "pixel_info")]
+    PixelInfo {
+        x: usize,
+        y: usize,
+        /// Who last painted the pixel, if it was painted since the last resize
+        author: Option<String>,
+        timestamp: Option<u64>,
+    },
+
+    #[serde(rename = "ack")]
+    Ack {
+        id: Option<u64>,
+    },
+
+    #[serde(rename = "error")]
+    Error {
+        id: Option<u64>,
+        code: ErrorCode,
+        message: String,
+    },
 }
@@ -1,97 +1,177 @@
+pub mod auth;
+mod batch;
+mod binary;
+mod error;
+mod http;
 mod messages;
+pub mod permissions;
 mod rate_limit;
+mod room;
 
 use tokio::net::{TcpListener, TcpStream};
 use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::handshake::server::{Callback, ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
 use futures_util::{StreamExt, SinkExt};
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
 use tokio::sync::RwLock;
 use std::collections::HashMap;
-use messages::{ClientMessage, ServerMessage};
-use crate::world::{World, color::Color};
+use batch::PendingUpdates;
+use binary::Protocol;
+use error::RequestError;
+use messages::{ClientEnvelope, ClientMessage, ErrorCode, ImageFormat, Role, ServerMessage};
+use permissions::Permission;
+use rate_limit::{ConnectionSlot, MessageKind, RateLimits};
+use room::{Room, RoomRegistry};
+use crate::world::{World, change::{ChangeEvent, PaintFilter}, color::Color};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum Role {
-    Admin,
-    Player,
-}
+/// Most colors a restricted palette may hold
+const MAX_ALLOWED_COLORS: usize = 256;
 
 struct ClientInfo {
     sender: tokio::sync::mpsc::UnboundedSender<Message>,
     role: Role,
+    protocol: Protocol,
+    /// Stable identity used to attribute changes, never the raw socket address
+    identity: String,
 }
 
-type Clients = Arc<RwLock<HashMap<SocketAddr, ClientInfo>>>;
+type Clients = RwLock<HashMap<SocketAddr, ClientInfo>>;
+
+/// What a client's WebSocket upgrade request carried
+#[derive(Default)]
+struct Handshake {
+    path: String,
+    params: HashMap<String, String>,
+    claims: Option<auth::Claims>,
+}
+
+/// Accepts or refuses an upgrade request, recording what it carried in `handshake`
+struct HandshakeCheck {
+    addr: SocketAddr,
+    handshake: Arc<Mutex<Handshake>>,
+}
+
+impl Callback for HandshakeCheck {
+    fn on_request(self, request: &Request, response: Response) -> Result<Response, ErrorResponse> {
+        let handshake = Server::inspect_handshake(request, self.addr).map_err(|refusal| *refusal)?;
+        *self.handshake.lock().unwrap() = handshake;
+        Ok(response)
+    }
+}
 
 pub struct Server {
     addr: String,
-    clients: Clients,
-    world: Arc<RwLock<World>>,
+    rooms: Arc<RoomRegistry>,
+    rate_limits: Arc<RateLimits>,
 }
 
 impl Server {
-    pub fn new(addr: impl Into<String>) -> Self {
-        let history = match crate::world::persistence::load_history() {
-            Ok(history) if !history.snapshots.is_empty() => {
-                println!("Loaded history from disk");
-                history
-            }
-            _ => {
-                println!("No valid history found, creating new world");
-                let canvas = crate::world::canvas::Canvas::new(
-                    crate::env::default_canvas_width(),
-                    crate::env::default_canvas_height()
-                ).expect("Failed to create canvas");
-                crate::world::history::History::new(
-                    crate::env::default_snapshot_interval(),
-                    &canvas
-                )
-            }
-        };
-        
-        let world = World::from(history);
-        
-        Self {
+    pub fn new(addr: impl Into<String>) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self {
             addr: addr.into(),
-            clients: Arc::new(RwLock::new(HashMap::new())),
-            world: Arc::new(RwLock::new(world)),
-        }
+            rooms: Arc::new(RoomRegistry::load()?),
+            rate_limits: Arc::new(RateLimits::new()),
+        })
     }
 
     pub async fn run(&self) -> Result<(), Box<dyn std::error::Error>> {
         let listener = TcpListener::bind(&self.addr).await?;
         println!("Server listening on {}", self.addr);
 
-        // Spawn periodic save task
-        let world_for_save = self.world.clone();
+        // Spawn periodic compaction task that folds write-ahead logs into history files
+        let rooms_for_save = self.rooms.clone();
         tokio::spawn(async move {
             let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(crate::env::autosave_interval()));
             loop {
                 interval.tick().await;
-                let world_lock = world_for_save.read().await;
-                if let Err(e) = crate::world::persistence::save_history(&world_lock.history) {
-                    eprintln!("Failed to save history: {}", e);
-                } else {
-                    println!("History saved to disk");
+                for room in rooms_for_save.all().await {
+                    if room.is_archived() {
+                        continue;
+                    }
+                    Self::prune_cooldowns(&room).await;
+                    // A smaller palette is worth saving even if nothing else changed
+                    let collected = Self::collect_palette(&room).await;
+                    if let Err(e) = room.save(collected).await {
+                        eprintln!("Failed to save history for room '{}': {}", room.name, e);
+                    }
+                }
+            }
+        });
+
+        // Spawn write-ahead log sync task, bounding how many changes a crash can lose
+        let rooms_for_sync = self.rooms.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(tokio::time::Duration::from_millis(crate::env::wal_sync_interval_ms()));
+            loop {
+                interval.tick().await;
+                for room in rooms_for_sync.all().await {
+                    if let Err(e) = room.sync_log().await {
+                        eprintln!("Failed to sync write-ahead log for room '{}': {}", room.name, e);
+                    }
+                }
+            }
+        });
+
+        // Spawn a task that forgets idle rate limit buckets, so they don't pile up per address
+        let limits_for_prune = self.rate_limits.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                limits_for_prune.prune();
+            }
+        });
+
+        // Spawn broadcast tick task that flushes batched updates
+        let rooms_for_tick = self.rooms.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(tokio::time::Duration::from_millis(crate::env::broadcast_tick_ms()));
+            loop {
+                interval.tick().await;
+                for room in rooms_for_tick.all().await {
+                    // Hold the world lock so a flush can't interleave with an Init broadcast
+                    let world_lock = room.world.read().await;
+                    let updates = room.pending.lock().unwrap().drain();
+                    if !updates.is_empty() {
+                        let seq = world_lock.change_count();
+                        Self::broadcast_to_all(&room.clients, &ServerMessage::Updates { seq, updates }).await;
+                    }
                 }
             }
         });
 
         while let Ok((stream, addr)) = listener.accept().await {
             println!("New connection from {}", addr);
-            tokio::spawn(Self::handle_connection(
-                stream, 
-                addr, 
-                self.clients.clone(), 
-                self.world.clone()
-            ));
+            let rooms = self.rooms.clone();
+            let rate_limits = self.rate_limits.clone();
+            tokio::spawn(async move {
+                // Held for as long as the connection lives, whatever it turns out to be
+                let Some(slot) = rate_limits.connect(addr.ip()) else {
+                    println!("Refusing connection from {}: too many connections from its address", addr);
+                    http::refuse(stream, "429 Too Many Requests", "Too many connections from this address").await;
+                    return;
+                };
+
+                // Plain HTTP image requests share the listener with WebSocket clients
+                match http::request_kind(&stream).await {
+                    http::RequestKind::Image => http::serve_image(stream, addr, slot, &rooms, &rate_limits).await,
+                    http::RequestKind::WebSocket => Self::handle_connection(stream, addr, slot, rooms, rate_limits).await,
+                    http::RequestKind::TooLong => {
+                        println!("Refusing connection from {}: request line too long", addr);
+                        http::refuse(stream, "414 URI Too Long", "Request line too long").await;
+                    }
+                }
+            });
         }
 
         Ok(())
     }
 
-    fn build_init_message(world: &World) -> ServerMessage {
+    /// Build the full board for a client that may paint again in `cooldown` milliseconds
+    fn build_init_message(world: &World, cooldown: u64) -> ServerMessage {
         let width = world.canvas.width();
         let height = world.canvas.height();
         
@@ -99,80 +179,157 @@ impl Server {
         let pixels = world.canvas.pixels();
         let palette_lock = world.canvas.palette();
         let palette = palette_lock.read().unwrap();
-        let palette_colors = palette.colors().to_vec();
+        let palette_colors = palette.colors().iter().map(|color| color.to_hex()).collect();
         
         ServerMessage::Init {
             width,
             height,
             palette: palette_colors,
             board: pixels.to_vec(),
-            cooldown: 0,
+            allowed_palette: Self::allowed_palette(world),
+            cooldown,
+            seq: world.change_count(),
         }
     }
 
-    async fn handle_connection(stream: TcpStream, addr: SocketAddr, clients: Clients, world: Arc<RwLock<World>>) {
-        use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
-        
-        // Extract query parameters from the WebSocket handshake
-        let query_params = Arc::new(Mutex::new(HashMap::new()));
-        let params_for_callback = query_params.clone();
-        
-        let ws_stream = match tokio_tungstenite::accept_hdr_async(stream, |req: &Request, resp: Response| {
-            // Extract query string from the request URI
-            if let Some(query_string) = req.uri().query() {
-                let mut params = HashMap::new();
-                for param in query_string.split('&') {
-                    if let Some((key, value)) = param.split_once('=') {
-                        params.insert(
-                            key.to_string(),
-                            urlencoding::decode(value).unwrap_or_default().to_string()
-                        );
+    /// Build the first message for a connecting client: the changes it missed if it
+    /// is resuming from a recent `since` sequence number, otherwise the full board
+    fn build_welcome_message(world: &World, since: Option<usize>, cooldown: u64) -> ServerMessage {
+        let missed = since.and_then(|since| world.history.changes_since(since));
+        match missed {
+            Some(changes) if changes.len() <= crate::env::resume_max_changes() => {
+                let mut replay = PendingUpdates::new();
+                for (offset, change) in changes.iter().enumerate() {
+                    match &change.event {
+                        ChangeEvent::Paint { x, y, color } => {
+                            replay.push(offset, *x, *y, color.to_hex());
+                        }
+                        ChangeEvent::Restore { pixels } => {
+                            for pixel in pixels {
+                                replay.push(offset, pixel.x, pixel.y, pixel.color.to_hex());
+                            }
+                        }
+                        _ => {}
                     }
                 }
-                if let Ok(mut p) = params_for_callback.lock() {
-                    *p = params;
+                ServerMessage::Updates {
+                    seq: world.change_count(),
+                    updates: replay.drain(),
                 }
             }
-            Ok(resp)
-        }).await {
+            _ => Self::build_init_message(world, cooldown),
+        }
+    }
+
+    /// Serve a WebSocket client, counted against its address group through `_slot`
+    async fn handle_connection(
+        stream: TcpStream,
+        addr: SocketAddr,
+        _slot: ConnectionSlot,
+        rooms: Arc<RoomRegistry>,
+        rate_limits: Arc<RateLimits>,
+    ) {
+        // Extract the path, query parameters and session token from the WebSocket handshake
+        let handshake = Arc::new(Mutex::new(Handshake::default()));
+        let check = HandshakeCheck {
+            addr,
+            handshake: handshake.clone(),
+        };
+        let ws_stream = match tokio_tungstenite::accept_hdr_async(stream, check).await {
             Ok(stream) => stream,
             Err(e) => {
                 eprintln!("WebSocket handshake failed with {}: {}", addr, e);
                 return;
             }
         };
-        
-        let query_params = query_params.lock().unwrap().clone();
-        println!("Query parameters for {}: {:?}", addr, query_params);
+        let Handshake { path: request_path, params: query_params, claims } = std::mem::take(&mut *handshake.lock().unwrap());
 
-        // Determine role based on auth parameter
-        let role = match query_params.get("auth") {
-            Some(token) if token == crate::env::admin_token() => Role::Admin,
-            _ => Role::Player,
+        let mut logged_params = query_params.clone();
+        for secret in ["auth", "token"] {
+            if let Some(value) = logged_params.get_mut(secret) {
+                *value = "<redacted>".to_string();
+            }
+        }
+        println!("Query parameters for {}: {:?}", addr, logged_params);
+
+        // Pick the room from the `room` query parameter, falling back to the request path
+        let room_name = match query_params.get("room") {
+            Some(name) => name.clone(),
+            None => Self::room_from_path(&request_path),
         };
-        println!("Client {} connected as {:?}", addr, role);
 
-        let (mut ws_sender, mut ws_receiver) = ws_stream.split();
-        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        // A verified session token decides the role and identity, otherwise the
+        // shared admin token and the client-chosen session id do
+        let protocol = Protocol::from_query(query_params.get("protocol"));
+        let (role, identity) = match &claims {
+            Some(claims) => (claims.role, claims.identity()),
+            None => {
+                let role = match query_params.get("auth") {
+                    Some(token) if token == crate::env::admin_token() => Role::Admin,
+                    _ => Role::Player,
+                };
+                (role, Self::session_identity(query_params.get("session")))
+            }
+        };
+        match &claims {
+            Some(claims) => println!(
+                "Client {} connected as {:?} ({}, \"{}\") using {:?} protocol",
+                addr, role, identity, claims.name, protocol
+            ),
+            None => println!("Client {} connected as {:?} ({}) using {:?} protocol", addr, role, identity, protocol),
+        }
 
-        // Store the client with their role
-        clients.write().await.insert(addr, ClientInfo {
-            sender: tx.clone(),
-            role,
-        });
+        let (mut ws_sender, mut ws_receiver) = ws_stream.split();
 
-        // Send init message immediately
-        let init_msg = {
-            let world_lock = world.read().await;
-            Self::build_init_message(&world_lock)
+        let Some(room) = rooms.get(&room_name).await else {
+            eprintln!("Client {} requested unknown room '{}'", addr, room_name);
+            Self::refuse_room(&mut ws_sender, &room_name, protocol).await;
+            return;
         };
-        
-        if let Ok(json) = serde_json::to_string(&init_msg) {
-            if tx.send(Message::Text(json)).is_err() {
+        println!("Client {} joined room '{}'", addr, room.name);
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+
+        // Queue init, or only the missed changes when resuming, before the client
+        // joins the broadcast set, so no tick can reach it first. Changes need the
+        // world write lock, so none can land between building the welcome and
+        // joining: every later `updates` has a `seq` no lower than the welcome's,
+        // and repeats of changes it already contains are harmless.
+        let since = query_params.get("since").and_then(|s| s.parse().ok());
+        {
+            let world_lock = room.world.read().await;
+            // Deletion marks the room under the world lock before disconnecting its
+            // clients, so anyone joining after that has to be turned away here
+            if room.is_deleted() {
+                drop(world_lock);
+                eprintln!("Client {} requested deleted room '{}'", addr, room_name);
+                Self::refuse_room(&mut ws_sender, &room_name, protocol).await;
+                return;
+            }
+            let cooldown_key = Self::cooldown_key(&identity, addr);
+            let cooldown = world_lock.cooldown_remaining(&cooldown_key, Self::paint_cooldown(&world_lock, role));
+            let init_msg = Self::build_welcome_message(&world_lock, since, cooldown);
+            if let Some(msg) = Self::encode(&init_msg, protocol)
+                && tx.send(msg).is_err()
+            {
                 eprintln!("Failed to send init message to {}", addr);
-                clients.write().await.remove(&addr);
                 return;
             }
+            // Resumed clients get `updates` instead of `init`, so tell them their cooldown separately
+            if matches!(init_msg, ServerMessage::Updates { .. })
+                && cooldown > 0
+                && let Some(msg) = Self::encode(&ServerMessage::Cooldown { cooldown }, protocol)
+            {
+                tx.send(msg).ok();
+            }
+
+            // Store the client with their role
+            room.clients.write().await.insert(addr, ClientInfo {
+                sender: tx.clone(),
+                role,
+                protocol,
+                identity: identity.clone(),
+            });
         }
 
         // Spawn task to handle outgoing messages
@@ -185,26 +342,25 @@ impl Server {
         });
 
 
-        let mut rate_limiter = rate_limit::RateLimiter::new(
-            crate::env::rate_limit_tokens(),
-            crate::env::rate_limit_refill_rate_ms()
-        );
-
         // Handle incoming messages
         while let Some(msg) = ws_receiver.next().await {
+            // Clients of a deleted room were sent a close; drop any that keep talking
+            if room.is_deleted() {
+                break;
+            }
             match msg {
                 Ok(Message::Text(text)) => {
-                    Self::handle_json_message(&clients, &world, &text, addr, &mut rate_limiter).await;
+                    Self::handle_json_message(&rooms, &room, &text, addr, &rate_limits).await;
                 }
                 Ok(Message::Binary(bin)) => {
-                    Self::broadcast_message(&clients, Message::Binary(bin), addr).await;
+                    Self::handle_binary_message(&rooms, &room, &bin, addr, &rate_limits).await;
                 }
                 Ok(Message::Close(_)) => {
                     println!("Client {} closed connection", addr);
                     break;
                 }
                 Ok(Message::Ping(data)) => {
-                    if let Some(client_info) = clients.read().await.get(&addr) {
+                    if let Some(client_info) = room.clients.read().await.get(&addr) {
                         client_info.sender.send(Message::Pong(data)).ok();
                     }
                 }
@@ -220,194 +376,683 @@ impl Server {
         // Clean up
         println!("Client {} disconnected", addr);
         send_task.abort();
-        clients.write().await.remove(&addr);
+        room.clients.write().await.remove(&addr);
+    }
+
+    /// Tell a client the room it asked for doesn't exist and close the connection
+    async fn refuse_room(ws_sender: &mut (impl SinkExt<Message> + Unpin), name: &str, protocol: Protocol) {
+        let error = room::room_not_found(name).into_message(None);
+        if let Some(msg) = Self::encode(&error, protocol) {
+            ws_sender.send(msg).await.ok();
+        }
+        ws_sender.send(Message::Close(None)).await.ok();
+    }
+
+    /// Check an upgrade request, returning what it carries or the response refusing it
+    fn inspect_handshake(req: &Request, addr: SocketAddr) -> Result<Handshake, Box<ErrorResponse>> {
+        // Extract query string from the request URI
+        let params = req.uri().query().map(http::parse_query).unwrap_or_default();
+
+        // Browsers can't set headers on WebSocket requests, so tokens may also come in the query
+        let bearer = req
+            .headers()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let claims = match params.get("token").map(String::as_str).or(bearer) {
+            Some(token) => match Self::verify_token(token) {
+                Ok(verified) => Some(verified),
+                Err(e) => {
+                    println!("Rejected session token from {}: {}", addr, e);
+                    let mut refusal = ErrorResponse::new(Some(e));
+                    *refusal.status_mut() = StatusCode::UNAUTHORIZED;
+                    return Err(Box::new(refusal));
+                }
+            },
+            None => None,
+        };
+
+        Ok(Handshake {
+            path: req.uri().path().to_string(),
+            params,
+            claims,
+        })
     }
 
-    async fn handle_json_message(clients: &Clients, world: &Arc<RwLock<World>>, text: &str, sender: SocketAddr, rate_limiter: &mut rate_limit::RateLimiter) {
-        let client_msg: ClientMessage = match serde_json::from_str(text) {
-            Ok(msg) => msg,
+    /// Check a session token against `SESSION_SECRET`
+    fn verify_token(token: &str) -> Result<auth::Claims, String> {
+        match crate::env::session_secret() {
+            Some(secret) => auth::verify(token, secret),
+            None => Err("Session tokens are not enabled on this server".to_string()),
+        }
+    }
+
+    /// Use the client-supplied session id if it looks sane, otherwise mint an anonymous one
+    fn session_identity(session: Option<&String>) -> String {
+        static NEXT_ANONYMOUS_ID: AtomicU64 = AtomicU64::new(0);
+
+        match session {
+            Some(session)
+                if !session.is_empty()
+                    && session.len() <= 64
+                    && session.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') =>
+            {
+                format!("session:{}", session)
+            }
+            _ => {
+                let started = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|d| d.as_millis())
+                    .unwrap_or(0);
+                let n = NEXT_ANONYMOUS_ID.fetch_add(1, Ordering::Relaxed);
+                format!("anon:{:x}-{}", started, n)
+            }
+        }
+    }
+
+    /// Map a request path such as `/`, `/my-room` or `/rooms/my-room` to a room name
+    fn room_from_path(path: &str) -> String {
+        let path = path.trim_matches('/');
+        let name = path.strip_prefix("rooms/").unwrap_or(path);
+        if name.is_empty() {
+            room::DEFAULT_ROOM.to_string()
+        } else {
+            urlencoding::decode(name).unwrap_or_default().to_string()
+        }
+    }
+
+    async fn handle_json_message(rooms: &RoomRegistry, room: &Room, text: &str, sender: SocketAddr, rate_limits: &RateLimits) {
+        let envelope: ClientEnvelope = match serde_json::from_str(text) {
+            Ok(envelope) => envelope,
             Err(e) => {
                 eprintln!("Failed to parse JSON from {}: {} ({})", sender, e, text);
+                // Still echo the request id if the payload was at least valid JSON
+                let id = serde_json::from_str::<serde_json::Value>(text)
+                    .ok()
+                    .and_then(|value| value.get("id").and_then(|id| id.as_u64()));
+                let error = RequestError::new(ErrorCode::InvalidMessage, e.to_string());
+                Self::send_to(&room.clients, sender, &error.into_message(id)).await;
                 return;
             }
         };
 
-        match client_msg {
-            ClientMessage::Paint { x, y, color } => {
-                let is_admin = {
-                    let clients_lock = clients.read().await;
-                    clients_lock.get(&sender).map(|info| info.role == Role::Admin).unwrap_or(false)
-                };
+        Self::dispatch(rooms, room, envelope, sender, rate_limits).await;
+    }
 
-                // Enforce rate limit for non-admin clients
-                if !is_admin && !rate_limiter.take() {
-                    eprintln!("Rate limit exceeded for client {}", sender);
-                    return;
-                }
+    async fn handle_binary_message(rooms: &RoomRegistry, room: &Room, data: &[u8], sender: SocketAddr, rate_limits: &RateLimits) {
+        let decoded = {
+            let world_lock = room.world.read().await;
+            let palette_lock = world_lock.canvas.palette();
+            let palette = palette_lock.read().unwrap();
+            let restricted = world_lock.history.allowed_colors().is_some();
+            binary::decode_client_message(data, &palette, restricted)
+        };
+
+        match decoded {
+            Ok(envelope) => Self::dispatch(rooms, room, envelope, sender, rate_limits).await,
+            Err(e) => {
+                eprintln!("Failed to decode binary message from {}: {}", sender, e.message);
+                Self::send_to(&room.clients, sender, &e.into_message(None)).await;
+            }
+        }
+    }
+
+    /// Handle a decoded client message and reply with an ack or an error
+    async fn dispatch(rooms: &RoomRegistry, room: &Room, envelope: ClientEnvelope, sender: SocketAddr, rate_limits: &RateLimits) {
+        let id = envelope.id;
+        let outcome = match Self::check_permission(room, &envelope.message, sender).await {
+            Ok(role) => match Self::check_rate_limit(room, &envelope.message, sender, role, rate_limits).await {
+                Ok(()) => Self::handle_client_message(rooms, room, envelope.message, sender).await,
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+        let reply = match outcome {
+            Ok(()) => ServerMessage::Ack { id },
+            Err(e) => {
+                eprintln!("Rejected message from {}: {:?} ({})", sender, e.code, e.message);
+                e.into_message(id)
+            }
+        };
+        Self::send_to(&room.clients, sender, &reply).await;
+    }
+
+    /// Check that the sender's role allows the message and that they aren't banned
+    /// from the room, returning their role
+    async fn check_permission(room: &Room, message: &ClientMessage, sender: SocketAddr) -> Result<Role, RequestError> {
+        let role = Self::role(&room.clients, sender).await;
+        let Some(permission) = Permission::required_for(message) else {
+            return Ok(role);
+        };
+        if !permissions::allows(role, permission) {
+            return Err(RequestError::new(
+                ErrorCode::Forbidden,
+                format!("The {} role does not have the {} permission", role.name(), permission.name()),
+            ));
+        }
+
+        // Admins can't be banned, so a stray ban can never lock everyone out
+        if role != Role::Admin {
+            let identity = Self::identity(&room.clients, sender).await;
+            if room.world.read().await.is_banned(&identity) {
+                return Err(RequestError::new(ErrorCode::Banned, format!("You are banned from room '{}'", room.name)));
+            }
+        }
+        Ok(role)
+    }
+
+    /// Take a token from the sender's address and user buckets for the message's
+    /// kind. Roles with `bypass-ratelimit` and messages without a bucket are never limited.
+    async fn check_rate_limit(room: &Room, message: &ClientMessage, sender: SocketAddr, role: Role, rate_limits: &RateLimits) -> Result<(), RequestError> {
+        let kind = match message {
+            ClientMessage::Paint { .. } => MessageKind::Paint,
+            ClientMessage::Ping => MessageKind::Ping,
+            ClientMessage::PixelInfo { .. } => MessageKind::Query,
+            _ => return Ok(()),
+        };
+        if permissions::allows(role, Permission::BypassRateLimit) {
+            return Ok(());
+        }
+
+        // Anonymous identities only last as long as their connection, so the address limit covers them
+        let identity = Self::identity(&room.clients, sender).await;
+        let user = (!identity.starts_with("anon:")).then_some(identity.as_str());
+        if rate_limits.take(kind, sender.ip(), user) {
+            Ok(())
+        } else {
+            Err(RequestError::new(
+                ErrorCode::RateLimited,
+                format!("Rate limit exceeded for {} messages", kind.name()),
+            ))
+        }
+    }
+
+    async fn handle_client_message(rooms: &RoomRegistry, room: &Room, client_msg: ClientMessage, sender: SocketAddr) -> Result<(), RequestError> {
+        match client_msg {
+            ClientMessage::Paint { x, y, color, index } => {
+                Self::ensure_writable(room)?;
+                let role = Self::role(&room.clients, sender).await;
+                let identity = Self::identity(&room.clients, sender).await;
+                let cooldown_key = Self::cooldown_key(&identity, sender);
 
-                // Validate color format
-                if !color.starts_with('#') || color.len() != 7 {
-                    eprintln!("Invalid color format from {}: {}", sender, color);
-                    return;
-                }
-                
-                // Parse color
-                let parsed_color = match Color::from_hex(&color) {
-                    Ok(c) => c,
-                    Err(e) => {
-                        eprintln!("Invalid color from {}: {} ({})", sender, color, e);
-                        return;
-                    }
-                };
-                
                 // Apply the paint operation
-                let color_for_broadcast = parsed_color.clone();
-                let result = {
-                    let mut world_lock = world.write().await;
-                    let paint_event = crate::world::change::ChangeEvent::Paint {
+                let cooldown = {
+                    let mut world_lock = room.world.write().await;
+                    // Checked under the write lock so two paints can't both pass
+                    let cooldown = Self::paint_cooldown(&world_lock, role);
+                    let remaining = world_lock.cooldown_remaining(&cooldown_key, cooldown);
+                    if remaining > 0 {
+                        return Err(RequestError::new(
+                            ErrorCode::Cooldown,
+                            format!("You can paint again in {} ms", remaining),
+                        ));
+                    }
+
+                    // Checked under the write lock so a palette change can't slip in between
+                    let parsed_color = Self::paint_color(&world_lock, color.as_deref(), index)?;
+                    let paint_event = ChangeEvent::Paint {
                         x,
                         y,
                         color: parsed_color,
                     };
-                    world_lock.apply_event(paint_event)
-                };
-                
-                match result {
-                    Ok(_) => {
-                        // Broadcast update to all clients (including sender)
-                        let update_msg = ServerMessage::Update {
-                            x,
-                            y,
-                            color: color_for_broadcast.to_hex().to_string(),
-                        };
-                        
-                        if let Ok(json) = serde_json::to_string(&update_msg) {
-                            Self::broadcast_to_all(clients, Message::Text(json)).await;
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("Failed to paint pixel from {}: {:?}", sender, e);
+                    world_lock.apply_event(paint_event, Some(identity))?;
+                    if cooldown > 0 {
+                        world_lock.start_cooldown(&cooldown_key);
                     }
+                    // Translucent paints are composited, so broadcast the color that landed
+                    let painted = world_lock.canvas.get_pixel(x, y)?;
+
+                    // Queue the update for the next broadcast tick while still holding
+                    // the world lock, so queued order matches history order
+                    let change_index = world_lock.change_count() - 1;
+                    room.pending.lock().unwrap().push(
+                        change_index,
+                        x,
+                        y,
+                        painted.to_hex(),
+                    );
+                    cooldown
+                };
+
+                if cooldown > 0 {
+                    Self::send_to(&room.clients, sender, &ServerMessage::Cooldown { cooldown }).await;
                 }
             }
             ClientMessage::Ping => {
                 // Respond with current client count
-                let client_count = clients.read().await.len();
+                let client_count = room.clients.read().await.len();
                 let pong_msg = ServerMessage::Pong {
                     clients: client_count,
                 };
                 
-                if let Ok(json) = serde_json::to_string(&pong_msg) {
-                    if let Some(client_info) = clients.read().await.get(&sender) {
-                        client_info.sender.send(Message::Text(json)).ok();
-                    }
-                }
+                Self::send_to(&room.clients, sender, &pong_msg).await;
             }
             ClientMessage::Resize { width, height, anchor } => {
-                // Check if sender is admin
-                let is_admin = {
-                    let clients_lock = clients.read().await;
-                    clients_lock.get(&sender)
-                        .map(|info| info.role == Role::Admin)
-                        .unwrap_or(false)
-                };
+                Self::ensure_writable(room)?;
+                let identity = Self::identity(&room.clients, sender).await;
                 
-                if !is_admin {
-                    eprintln!("Non-admin client {} attempted to resize canvas", sender);
-                    return;
-                }
-                
-                // Validate dimensions
-                if width == 0 || height == 0 {
-                    eprintln!("Invalid resize dimensions from {}: {}x{}", sender, width, height);
-                    return;
-                }
+                room::check_dimensions(width, height)?;
                 
                 // Apply the resize operation
-                let result = {
-                    let mut world_lock = world.write().await;
-                    let resize_event = crate::world::change::ChangeEvent::Resize {
+                {
+                    let mut world_lock = room.world.write().await;
+                    let resize_event = ChangeEvent::Resize {
                         anchor,
                         width,
                         height,
                     };
-                    world_lock.apply_event(resize_event)
-                };
-                
-                match result {
-                    Ok(_) => {
-                        // Send new Init message to all clients with updated board state
-                        let init_msg = {
-                            let world_lock = world.read().await;
-                            Self::build_init_message(&world_lock)
-                        };
-                        
-                        if let Ok(json) = serde_json::to_string(&init_msg) {
-                            Self::broadcast_to_all(clients, Message::Text(json)).await;
-                        }
-                        
-                        println!("Canvas resized to {}x{} with anchor {:?} by admin {}", width, height, anchor, sender);
-                    }
-                    Err(e) => {
-                        eprintln!("Failed to resize canvas from {}: {:?}", sender, e);
-                    }
+                    world_lock.apply_event(resize_event, Some(identity))?;
+
+                    // Send new Init message to all clients with updated board state
+                    Self::broadcast_init(room, &world_lock).await;
                 }
+                
+                println!("Room '{}' resized to {}x{} with anchor {:?} by {}", room.name, width, height, anchor, sender);
             }
             ClientMessage::Rollback { target_index } => {
-                // Check if sender is admin
-                let is_admin = {
-                    let clients_lock = clients.read().await;
-                    clients_lock.get(&sender)
-                        .map(|info| info.role == Role::Admin)
-                        .unwrap_or(false)
-                };
-                
-                if !is_admin {
-                    eprintln!("Non-admin client {} attempted to rollback canvas", sender);
-                    return;
-                }
+                Self::ensure_writable(room)?;
+                let identity = Self::identity(&room.clients, sender).await;
                 
                 // Apply the rollback operation
-                let result = {
-                    let mut world_lock = world.write().await;
-                    world_lock.rollback_to_index(target_index)
-                };
+                {
+                    let mut world_lock = room.world.write().await;
+                    world_lock.rollback_to_index(target_index, Some(identity))?;
+
+                    // Send new Init message to all clients with rolled-back board state
+                    Self::broadcast_init(room, &world_lock).await;
+                }
                 
-                match result {
-                    Ok(_) => {
-                        // Send new Init message to all clients with rolled-back board state
-                        let init_msg = {
-                            let world_lock = world.read().await;
-                            Self::build_init_message(&world_lock)
-                        };
-                        
-                        if let Ok(json) = serde_json::to_string(&init_msg) {
-                            Self::broadcast_to_all(clients, Message::Text(json)).await;
+                println!("Room '{}' rolled back to index {} by {}", room.name, target_index, sender);
+            }
+            ClientMessage::UndoRollback => {
+                Self::ensure_writable(room)?;
+                let identity = Self::identity(&room.clients, sender).await;
+
+                {
+                    let mut world_lock = room.world.write().await;
+                    world_lock.undo_rollback(Some(identity))?;
+                    Self::broadcast_init(room, &world_lock).await;
+                }
+
+                println!("Room '{}' rollback undone by {}", room.name, sender);
+            }
+            ClientMessage::RedoRollback => {
+                Self::ensure_writable(room)?;
+                let identity = Self::identity(&room.clients, sender).await;
+
+                {
+                    let mut world_lock = room.world.write().await;
+                    world_lock.redo_rollback(Some(identity))?;
+                    Self::broadcast_init(room, &world_lock).await;
+                }
+
+                println!("Room '{}' rollback redone by {}", room.name, sender);
+            }
+            ClientMessage::Import { x, y, format, data, width, height } => {
+                Self::ensure_writable(room)?;
+                let identity = Self::identity(&room.clients, sender).await;
+
+                use base64::Engine;
+                let bytes = base64::engine::general_purpose::STANDARD
+                    .decode(data.as_bytes())
+                    .map_err(|e| RequestError::new(ErrorCode::InvalidImage, format!("Invalid base64 data: {}", e)))?;
+                let image = match format {
+                    ImageFormat::Png => crate::world::image::decode_png(&bytes),
+                    ImageFormat::Rgb => match (width, height) {
+                        (Some(width), Some(height)) => crate::world::image::decode_rgb(&bytes, width, height),
+                        _ => Err("Raw RGB imports need a width and height".to_string()),
+                    },
+                }
+                .map_err(|e| RequestError::new(ErrorCode::InvalidImage, e))?;
+                let (image_width, image_height) = (image.width, image.height);
+
+                {
+                    let mut world_lock = room.world.write().await;
+                    let import_event = ChangeEvent::Import {
+                        x,
+                        y,
+                        width: image.width,
+                        height: image.height,
+                        pixels: image.pixels,
+                    };
+                    world_lock.apply_event(import_event, Some(identity))?;
+
+                    // Imports touch many pixels at once, so resend the whole board
+                    Self::broadcast_init(room, &world_lock).await;
+                }
+
+                println!("Room '{}': {} imported a {}x{} image at ({}, {})", room.name, sender, image_width, image_height, x, y);
+            }
+            ClientMessage::RevertPaints { author, region, from, to } => {
+                Self::ensure_writable(room)?;
+                let identity = Self::identity(&room.clients, sender).await;
+
+                let filter = PaintFilter { author, region, from, to };
+                if filter.is_empty() {
+                    return Err(RequestError::new(
+                        ErrorCode::InvalidFilter,
+                        "Give at least one of author, region, from or to",
+                    ));
+                }
+
+                let restored = {
+                    let mut world_lock = room.world.write().await;
+                    let pixels = world_lock.revert_matching(&filter, Some(identity))?;
+
+                    // Broadcast the restored pixels as regular batched updates
+                    if !pixels.is_empty() {
+                        let change_index = world_lock.change_count() - 1;
+                        let mut pending = room.pending.lock().unwrap();
+                        for pixel in &pixels {
+                            pending.push(change_index, pixel.x, pixel.y, pixel.color.to_hex());
                         }
-                        
-                        println!("Canvas rolled back to index {} by admin {}", target_index, sender);
                     }
-                    Err(e) => {
-                        eprintln!("Failed to rollback canvas from {}: {:?}", sender, e);
+                    pixels.len()
+                };
+
+                println!("Room '{}': {} reverted {} pixel(s) matching {:?}", room.name, sender, restored, filter);
+            }
+            ClientMessage::PixelInfo { x, y } => {
+                let info = {
+                    let world_lock = room.world.read().await;
+                    let (width, height) = world_lock.dimensions();
+                    if x >= width || y >= height {
+                        return Err(crate::world::canvas::CanvasError::OutOfBounds { width, height }.into());
                     }
+                    let last_paint = world_lock.last_paint_at(x, y);
+                    ServerMessage::PixelInfo {
+                        x,
+                        y,
+                        author: last_paint.and_then(|change| change.author.clone()),
+                        timestamp: last_paint.map(|change| change.timestamp),
+                    }
+                };
+
+                Self::send_to(&room.clients, sender, &info).await;
+            }
+            ClientMessage::SetPalette { colors } => {
+                Self::ensure_writable(room)?;
+                let identity = Self::identity(&room.clients, sender).await;
+                let colors = colors.map(Self::parse_allowed_colors).transpose()?;
+                let count = colors.as_ref().map(Vec::len);
+
+                {
+                    let mut world_lock = room.world.write().await;
+                    world_lock.apply_event(ChangeEvent::SetPalette { colors }, Some(identity))?;
+                    let notice = ServerMessage::Palette { colors: Self::allowed_palette(&world_lock) };
+                    Self::broadcast_to_all(&room.clients, &notice).await;
                 }
+
+                match count {
+                    Some(count) => println!("Room '{}' palette restricted to {} color(s) by {}", room.name, count, sender),
+                    None => println!("Room '{}' palette restriction lifted by {}", room.name, sender),
+                }
+            }
+            ClientMessage::SetCooldown { role, cooldown } => {
+                Self::ensure_writable(room)?;
+
+                room.world.write().await.set_cooldown(role.name(), cooldown)?;
+
+                match cooldown {
+                    Some(cooldown) => println!("Room '{}' {} cooldown set to {} ms by {}", room.name, role.name(), cooldown, sender),
+                    None => println!("Room '{}' {} cooldown reset to the default by {}", room.name, role.name(), sender),
+                }
+            }
+            ClientMessage::Ban { identity, duration_ms } => {
+                Self::ensure_writable(room)?;
+                if identity.is_empty() {
+                    return Err(RequestError::new(ErrorCode::InvalidMessage, "Give the identity to ban"));
+                }
+                // Anonymous and session identities are chosen by the client, so banning one is pointless
+                if !identity.starts_with("user:") {
+                    return Err(RequestError::new(
+                        ErrorCode::InvalidMessage,
+                        "Only session token identities (user:<id>) can be banned",
+                    ));
+                }
+
+                room.world.write().await.ban(&identity, duration_ms)?;
+
+                match duration_ms {
+                    Some(duration_ms) => println!("Room '{}': {} banned for {} ms by {}", room.name, identity, duration_ms, sender),
+                    None => println!("Room '{}': {} banned by {}", room.name, identity, sender),
+                }
+            }
+            ClientMessage::Unban { identity } => {
+                Self::ensure_writable(room)?;
+
+                if !room.world.write().await.unban(&identity)? {
+                    return Ok(());
+                }
+
+                println!("Room '{}': {} unbanned by {}", room.name, identity, sender);
+            }
+            ClientMessage::CreateRoom { name, width, height, palette, cooldowns } => {
+                let width = width.unwrap_or_else(crate::env::default_canvas_width);
+                let height = height.unwrap_or_else(crate::env::default_canvas_height);
+                let allowed_colors = palette.map(Self::parse_allowed_colors).transpose()?;
+                rooms.create(&name, width, height, allowed_colors, cooldowns.unwrap_or_default()).await?;
+                println!("Room '{}' created with size {}x{} by {}", name, width, height, sender);
+            }
+            ClientMessage::ArchiveRoom { name } => {
+                rooms.archive(&name).await?;
+                println!("Room '{}' archived by {}", name, sender);
+            }
+            ClientMessage::DeleteRoom { name } => {
+                // Disconnect everyone still in the deleted room
+                let deleted = rooms.delete(&name).await?;
+                let notice = RequestError::new(ErrorCode::RoomNotFound, format!("Room '{}' was deleted", name));
+                Self::broadcast_to_all(&deleted.clients, &notice.into_message(None)).await;
+                for client_info in deleted.clients.read().await.values() {
+                    client_info.sender.send(Message::Close(None)).ok();
+                }
+                println!("Room '{}' deleted by {}", name, sender);
             }
         }
+
+        Ok(())
     }
 
-    async fn broadcast_message(clients: &Clients, msg: Message, sender: SocketAddr) {
-        let clients = clients.read().await;
-        for (addr, client_info) in clients.iter() {
-            if *addr != sender {
-                client_info.sender.send(msg.clone()).ok();
+    /// Send the full board to everyone in the room, superseding any updates
+    /// still waiting for the next tick. Call while holding the world write lock.
+    async fn broadcast_init(room: &Room, world: &World) {
+        room.pending.lock().unwrap().clear();
+
+        // Cooldowns differ per client, but most are zero, so share encodings where they match
+        let mut encoded: HashMap<(Protocol, u64), Option<Message>> = HashMap::new();
+        let clients = room.clients.read().await;
+        for (&addr, client_info) in clients.iter() {
+            let cooldown_key = Self::cooldown_key(&client_info.identity, addr);
+            let cooldown = world.cooldown_remaining(&cooldown_key, Self::paint_cooldown(world, client_info.role));
+            let message = encoded
+                .entry((client_info.protocol, cooldown))
+                .or_insert_with(|| Self::encode(&Self::build_init_message(world, cooldown), client_info.protocol));
+            if let Some(message) = message {
+                client_info.sender.send(message.clone()).ok();
+            }
+        }
+    }
+
+    /// What a client's paint cooldown is tracked by. Signed-in users keep theirs
+    /// across connections by identity; anonymous and `session` identities are
+    /// new or client-chosen on every connection, so those clients share the
+    /// cooldown of their address group instead.
+    fn cooldown_key(identity: &str, addr: SocketAddr) -> String {
+        if identity.starts_with("user:") {
+            identity.to_string()
+        } else {
+            format!("addr:{}", rate_limit::group_address(addr.ip()))
+        }
+    }
+
+    /// Milliseconds `role` must wait between paints: the room's setting, else the server default
+    fn paint_cooldown(world: &World, role: Role) -> u64 {
+        world.history.cooldown(role.name()).unwrap_or_else(|| match role {
+            Role::Admin => crate::env::admin_paint_cooldown_ms(),
+            Role::Moderator => crate::env::moderator_paint_cooldown_ms(),
+            Role::Player | Role::Viewer => crate::env::paint_cooldown_ms(),
+        })
+    }
+
+    /// Forget paints that no role's cooldown in the room reaches back to anymore
+    async fn prune_cooldowns(room: &Room) {
+        let mut world_lock = room.world.write().await;
+        let longest = [Role::Admin, Role::Moderator, Role::Player, Role::Viewer]
+            .into_iter()
+            .map(|role| Self::paint_cooldown(&world_lock, role))
+            .max()
+            .unwrap_or(0);
+        world_lock.prune_cooldowns(longest);
+    }
+
+    /// Drop the colors nothing refers to anymore from a room's palette and send
+    /// everyone the renumbered palette. Returns whether the palette changed.
+    async fn collect_palette(room: &Room) -> bool {
+        let mut world_lock = room.world.write().await;
+        let Some(dropped) = world_lock.collect_palette(crate::env::palette_gc_min_unused()) else {
+            return false;
+        };
+
+        let palette = world_lock.canvas.palette();
+        let palette = palette.read().unwrap().colors().iter().map(|color| color.to_hex()).collect::<Vec<_>>();
+        println!(
+            "Dropped {} unused color(s) from the palette of room '{}', {} left",
+            dropped,
+            room.name,
+            palette.len()
+        );
+        Self::broadcast_to_all(&room.clients, &ServerMessage::BoardPalette { palette }).await;
+        true
+    }
+
+    /// The restricted palette as hex strings, if the canvas has one
+    fn allowed_palette(world: &World) -> Option<Vec<String>> {
+        world
+            .history
+            .allowed_colors()
+            .map(|colors| colors.iter().map(|color| color.to_hex()).collect())
+    }
+
+    /// Validate the colors of a restricted palette: between 1 and
+    /// `MAX_ALLOWED_COLORS` distinct opaque colors
+    fn parse_allowed_colors(colors: Vec<String>) -> Result<Vec<Color>, RequestError> {
+        if colors.is_empty() || colors.len() > MAX_ALLOWED_COLORS {
+            return Err(RequestError::new(
+                ErrorCode::InvalidPalette,
+                format!("A palette needs between 1 and {} colors, got {}", MAX_ALLOWED_COLORS, colors.len()),
+            ));
+        }
+
+        let mut parsed: Vec<Color> = Vec::with_capacity(colors.len());
+        for color in &colors {
+            let value = Color::parse(color).map_err(|e| RequestError::new(ErrorCode::InvalidPalette, e))?;
+            if !value.is_opaque() {
+                return Err(RequestError::new(
+                    ErrorCode::InvalidPalette,
+                    format!("Palette colors must be opaque, got {}", color),
+                ));
+            }
+            if parsed.contains(&value) {
+                return Err(RequestError::new(
+                    ErrorCode::InvalidPalette,
+                    format!("Color {} appears more than once", color),
+                ));
+            }
+            parsed.push(value);
+        }
+        Ok(parsed)
+    }
+
+    /// Resolve the color of a paint given by exactly one of a color string or an
+    /// index into the restricted palette, checking it against that palette
+    fn paint_color(world: &World, color: Option<&str>, index: Option<usize>) -> Result<Color, RequestError> {
+        let allowed = world.history.allowed_colors();
+        match (color, index, allowed) {
+            (Some(_), Some(_), _) | (None, None, _) => Err(RequestError::new(
+                ErrorCode::InvalidMessage,
+                "Give exactly one of color or index",
+            )),
+            (None, Some(_), None) => Err(RequestError::new(
+                ErrorCode::InvalidColor,
+                "This canvas has no restricted palette; paint with a color",
+            )),
+            (None, Some(index), Some(allowed)) => allowed.get(index).copied().ok_or_else(|| {
+                RequestError::new(
+                    ErrorCode::InvalidColor,
+                    format!("Palette index {} is out of range (the palette has {} colors)", index, allowed.len()),
+                )
+            }),
+            (Some(color), None, allowed) => {
+                let parsed = Color::parse(color).map_err(|e| RequestError::new(ErrorCode::InvalidColor, e))?;
+                match allowed {
+                    Some(allowed) if !allowed.contains(&parsed) => Err(RequestError::new(
+                        ErrorCode::InvalidColor,
+                        format!("Color {} is not in this canvas's palette", color),
+                    )),
+                    _ => Ok(parsed),
+                }
             }
         }
     }
+
+    fn ensure_writable(room: &Room) -> Result<(), RequestError> {
+        if room.is_deleted() {
+            return Err(RequestError::new(ErrorCode::RoomNotFound, format!("Room '{}' was deleted", room.name)));
+        }
+        if room.is_archived() {
+            return Err(RequestError::new(
+                ErrorCode::RoomArchived,
+                format!("Room '{}' is archived and read-only", room.name),
+            ));
+        }
+        Ok(())
+    }
+
+    async fn role(clients: &Clients, addr: SocketAddr) -> Role {
+        let clients_lock = clients.read().await;
+        clients_lock.get(&addr)
+            .map(|info| info.role)
+            .unwrap_or(Role::Player)
+    }
+
+    async fn identity(clients: &Clients, addr: SocketAddr) -> String {
+        let clients_lock = clients.read().await;
+        clients_lock.get(&addr)
+            .map(|info| info.identity.clone())
+            .unwrap_or_default()
+    }
+
+    /// Encode a server message for a connection's negotiated protocol
+    fn encode(msg: &ServerMessage, protocol: Protocol) -> Option<Message> {
+        if protocol == Protocol::Binary
+            && let Some(bytes) = binary::encode_server_message(msg)
+        {
+            return Some(Message::Binary(bytes));
+        }
+        serde_json::to_string(msg).ok().map(Message::Text)
+    }
+
+    async fn send_to(clients: &Clients, addr: SocketAddr, msg: &ServerMessage) {
+        if let Some(client_info) = clients.read().await.get(&addr)
+            && let Some(encoded) = Self::encode(msg, client_info.protocol)
+        {
+            client_info.sender.send(encoded).ok();
+        }
+    }
     
-    async fn broadcast_to_all(clients: &Clients, msg: Message) {
+    async fn broadcast_to_all(clients: &Clients, msg: &ServerMessage) {
+        // Encode at most once per protocol
+        let mut json = None;
+        let mut binary = None;
         let clients = clients.read().await;
         for client_info in clients.values() {
-            client_info.sender.send(msg.clone()).ok();
+            let slot = match client_info.protocol {
+                Protocol::Json => &mut json,
+                Protocol::Binary => &mut binary,
+            };
+            if let Some(encoded) = slot.get_or_insert_with(|| Self::encode(msg, client_info.protocol)) {
+                client_info.sender.send(encoded.clone()).ok();
+            }
         }
     }
 }
@@ -0,0 +1,83 @@
+use super::messages::{ClientMessage, Role};
+
+/// Actions a role may be allowed to take
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    Paint,
+    /// Skip the per-address and per-user rate limits
+    BypassRateLimit,
+    /// Revert only the paints matching a filter
+    SelectiveRevert,
+    Resize,
+    /// Roll back, and undo or redo rollbacks
+    Rollback,
+    Import,
+    /// Change a room's palette restriction and cooldowns
+    ConfigureRoom,
+    Ban,
+    /// Create, archive and delete rooms
+    ManageRooms,
+}
+
+impl Permission {
+    pub const ALL: [Permission; 9] = [
+        Permission::Paint,
+        Permission::BypassRateLimit,
+        Permission::SelectiveRevert,
+        Permission::Resize,
+        Permission::Rollback,
+        Permission::Import,
+        Permission::ConfigureRoom,
+        Permission::Ban,
+        Permission::ManageRooms,
+    ];
+
+    /// Name used in configuration and error messages
+    pub fn name(self) -> &'static str {
+        match self {
+            Permission::Paint => "paint",
+            Permission::BypassRateLimit => "bypass-ratelimit",
+            Permission::SelectiveRevert => "selective-revert",
+            Permission::Resize => "resize",
+            Permission::Rollback => "rollback",
+            Permission::Import => "import",
+            Permission::ConfigureRoom => "configure-room",
+            Permission::Ban => "ban",
+            Permission::ManageRooms => "manage-rooms",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|permission| permission.name() == name)
+    }
+
+    /// The permission a message needs, or `None` if anyone may send it
+    pub fn required_for(message: &ClientMessage) -> Option<Self> {
+        match message {
+            ClientMessage::Paint { .. } => Some(Permission::Paint),
+            ClientMessage::Ping | ClientMessage::PixelInfo { .. } => None,
+            ClientMessage::Resize { .. } => Some(Permission::Resize),
+            ClientMessage::Rollback { .. } | ClientMessage::UndoRollback | ClientMessage::RedoRollback => {
+                Some(Permission::Rollback)
+            }
+            ClientMessage::Import { .. } => Some(Permission::Import),
+            ClientMessage::RevertPaints { .. } => Some(Permission::SelectiveRevert),
+            ClientMessage::SetPalette { .. } | ClientMessage::SetCooldown { .. } => Some(Permission::ConfigureRoom),
+            ClientMessage::Ban { .. } | ClientMessage::Unban { .. } => Some(Permission::Ban),
+            ClientMessage::CreateRoom { .. } | ClientMessage::ArchiveRoom { .. } | ClientMessage::DeleteRoom { .. } => {
+                Some(Permission::ManageRooms)
+            }
+        }
+    }
+}
+
+/// Whether `role` has `permission`. Admins can do everything; the other roles
+/// use the `<ROLE>_PERMISSIONS` settings.
+pub fn allows(role: Role, permission: Permission) -> bool {
+    match role {
+        Role::Admin => true,
+        Role::Moderator => crate::env::moderator_permissions().contains(&permission),
+        Role::Player => crate::env::player_permissions().contains(&permission),
+        Role::Viewer => crate::env::viewer_permissions().contains(&permission),
+    }
+}
@@ -1,3 +1,6 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
 use std::time::Instant;
 
 pub struct RateLimiter {
@@ -17,10 +20,11 @@ impl RateLimiter {
         }
     }
 
-    pub fn take(&mut self) -> bool {
+    /// Add the tokens earned since the last update
+    fn refill(&mut self) {
         let now = Instant::now();
         let elapsed_ms = now.duration_since(self.last_update).as_secs_f64() * 1000.0;
-        
+
         // Refill tokens based on elapsed time
         if self.refill_rate_ms > 0.0 {
             let added_tokens = elapsed_ms / self.refill_rate_ms;
@@ -30,7 +34,10 @@ impl RateLimiter {
             }
             self.last_update = now;
         }
+    }
 
+    pub fn take(&mut self) -> bool {
+        self.refill();
         if self.tokens >= 1.0 {
             self.tokens -= 1.0;
             true
@@ -38,4 +45,196 @@ impl RateLimiter {
             false
         }
     }
+
+    /// Whether the bucket has refilled completely, so forgetting it changes nothing
+    fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.capacity
+    }
+}
+
+/// Kinds of client messages that are limited by separate buckets
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageKind {
+    Paint,
+    Ping,
+    /// Read-only lookups such as `pixel_info`
+    Query,
+    /// `canvas.png` requests over plain HTTP
+    Image,
+}
+
+impl MessageKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            MessageKind::Paint => "paint",
+            MessageKind::Ping => "ping",
+            MessageKind::Query => "query",
+            MessageKind::Image => "image",
+        }
+    }
+
+    /// Bucket capacity and milliseconds per refilled token
+    fn limits(self) -> (f64, f64) {
+        match self {
+            MessageKind::Paint => (crate::env::rate_limit_tokens(), crate::env::rate_limit_refill_rate_ms()),
+            MessageKind::Ping => (crate::env::ping_rate_limit_tokens(), crate::env::ping_rate_limit_refill_rate_ms()),
+            MessageKind::Query => (crate::env::query_rate_limit_tokens(), crate::env::query_rate_limit_refill_rate_ms()),
+            MessageKind::Image => (crate::env::image_rate_limit_tokens(), crate::env::image_rate_limit_refill_rate_ms()),
+        }
+    }
+}
+
+/// Who a bucket belongs to
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Subject {
+    /// An IPv4 address, or an IPv6 address masked to its configured prefix
+    Ip(IpAddr),
+    User(String),
+}
+
+/// Token buckets shared by every connection, keyed by client address and by
+/// user, so opening more connections doesn't raise anyone's budget
+pub struct RateLimits {
+    buckets: Mutex<HashMap<(Subject, MessageKind), RateLimiter>>,
+    connections: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl RateLimits {
+    pub fn new() -> Self {
+        Self {
+            buckets: Mutex::new(HashMap::new()),
+            connections: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a token for a `kind` message from `ip`, sent by `user` if the client
+    /// has a stable identity. Nothing is taken unless every bucket involved has one.
+    pub fn take(&self, kind: MessageKind, ip: IpAddr, user: Option<&str>) -> bool {
+        self.take_within(kind, ip, user, kind.limits(), crate::env::rate_limit_ip_multiplier())
+    }
+
+    /// `take` with the bucket limits given rather than read from the environment
+    fn take_within(
+        &self,
+        kind: MessageKind,
+        ip: IpAddr,
+        user: Option<&str>,
+        (capacity, refill_rate_ms): (f64, f64),
+        ip_multiplier: f64,
+    ) -> bool {
+        let mut subjects = Vec::new();
+        // Session token users can't mint new identities, so their own bucket is
+        // enough and they don't share one with everyone behind their address
+        if !user.is_some_and(|user| user.starts_with("user:")) {
+            subjects.push((Subject::Ip(group_address(ip)), capacity * ip_multiplier));
+        }
+        if let Some(user) = user {
+            subjects.push((Subject::User(user.to_string()), capacity));
+        }
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let allowed = subjects.iter().all(|(subject, capacity)| {
+            let bucket = buckets
+                .entry((subject.clone(), kind))
+                .or_insert_with(|| RateLimiter::new(*capacity, refill_rate_ms));
+            bucket.refill();
+            bucket.tokens >= 1.0
+        });
+        if allowed {
+            for (subject, _) in subjects {
+                if let Some(bucket) = buckets.get_mut(&(subject, kind)) {
+                    bucket.take();
+                }
+            }
+        }
+        allowed
+    }
+
+    /// Forget buckets that have refilled completely
+    pub fn prune(&self) {
+        self.buckets.lock().unwrap().retain(|_, bucket| !bucket.is_full());
+    }
+
+    /// Reserve a connection slot for `ip`, or `None` if its address group is
+    /// already at `MAX_CONNECTIONS_PER_IP`. The slot is released when dropped.
+    pub fn connect(self: &Arc<Self>, ip: IpAddr) -> Option<ConnectionSlot> {
+        let group = group_address(ip);
+        let mut connections = self.connections.lock().unwrap();
+        let count = connections.entry(group).or_insert(0);
+        let max = crate::env::max_connections_per_ip();
+        if max > 0 && *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(ConnectionSlot {
+            limits: self.clone(),
+            group,
+        })
+    }
+}
+
+/// A connection counted against its address group until dropped
+pub struct ConnectionSlot {
+    limits: Arc<RateLimits>,
+    group: IpAddr,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut connections = self.limits.connections.lock().unwrap();
+        if let Some(count) = connections.get_mut(&self.group) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&self.group);
+            }
+        }
+    }
+}
+
+/// IPv6 clients usually get a whole prefix, so group addresses by the first
+/// `RATE_LIMIT_IPV6_PREFIX` bits. IPv4-mapped addresses count as IPv4.
+pub fn group_address(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => {
+                let prefix = crate::env::rate_limit_ipv6_prefix();
+                let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+                IpAddr::V6((u128::from(v6) & mask).into())
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const NAT: IpAddr = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7));
+    const LIMITS: (f64, f64) = (5.0, 1_000_000.0);
+
+    fn paints(limits: &RateLimits, user: Option<&str>, ip_multiplier: f64) -> usize {
+        (0..10).filter(|_| limits.take_within(MessageKind::Paint, NAT, user, LIMITS, ip_multiplier)).count()
+    }
+
+    #[test]
+    fn signed_in_users_behind_one_address_have_their_own_budgets() {
+        let limits = RateLimits::new();
+        assert_eq!(paints(&limits, Some("user:alice"), 1.0), 5);
+        assert_eq!(paints(&limits, Some("user:bob"), 1.0), 5);
+    }
+
+    #[test]
+    fn other_clients_behind_one_address_share_its_budget() {
+        let limits = RateLimits::new();
+        assert_eq!(paints(&limits, Some("session:a"), 2.0), 5);
+        assert_eq!(paints(&limits, Some("session:b"), 2.0), 5);
+        assert_eq!(paints(&limits, Some("session:c"), 2.0), 0);
+        assert_eq!(paints(&limits, None, 2.0), 0);
+        // Signed-in users don't draw from the exhausted address bucket
+        assert_eq!(paints(&limits, Some("user:alice"), 2.0), 5);
+    }
 }
@@ -0,0 +1,335 @@
+use super::batch::PendingUpdates;
+use super::error::RequestError;
+use super::messages::{ErrorCode, Role};
+use super::Clients;
+use crate::world::World;
+use crate::world::canvas::Canvas;
+use crate::world::color::Color;
+use crate::world::history::{History, RetentionPolicy};
+use crate::world::wal::{self, WriteAheadLog};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::RwLock;
+
+/// Name of the room clients join when they don't ask for one
+pub const DEFAULT_ROOM: &str = "default";
+
+/// A named canvas with its own world, connected clients and persistence file
+pub struct Room {
+    pub name: String,
+    pub world: RwLock<World>,
+    pub clients: Clients,
+    pub pending: Mutex<PendingUpdates>,
+    persistence_path: String,
+    archived: AtomicBool,
+    /// Set under the world lock once the room's files are about to be removed
+    deleted: AtomicBool,
+}
+
+impl Room {
+    fn new(name: &str, world: World, archived: bool) -> Self {
+        Self {
+            name: name.to_string(),
+            world: RwLock::new(world),
+            clients: RwLock::new(HashMap::new()),
+            pending: Mutex::new(PendingUpdates::new()),
+            persistence_path: room_persistence_path(name),
+            archived: AtomicBool::new(archived),
+            deleted: AtomicBool::new(false),
+        }
+    }
+
+    /// Archived rooms can still be viewed but no longer accept changes
+    pub fn is_archived(&self) -> bool {
+        self.archived.load(Ordering::Relaxed)
+    }
+
+    /// Deleted rooms accept no changes and are never written back to disk
+    pub fn is_deleted(&self) -> bool {
+        self.deleted.load(Ordering::Relaxed)
+    }
+
+    /// Apply the retention policy, then compact the room's write-ahead log
+    /// into its persistence file. Unless `force` is set, nothing is written
+    /// if nothing changed since the last save. Deleted rooms are never saved.
+    pub async fn save(&self, force: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut world_lock = self.world.write().await;
+        if self.is_deleted() {
+            return Ok(());
+        }
+        let pruned = world_lock.prune_history(&retention_policy());
+        if let Some(segment) = &pruned {
+            println!(
+                "Pruned changes {}..{} from room '{}'",
+                segment.first_retained_index(),
+                segment.current_change_count(),
+                self.name
+            );
+            self.archive_segment(segment);
+        }
+        world_lock.compact(&self.persistence_path, force || pruned.is_some())
+    }
+
+    /// Write a pruned history segment to the archive directory, if one is configured
+    fn archive_segment(&self, segment: &History) {
+        let Some(dir) = crate::env::history_archive_dir() else {
+            return;
+        };
+        let path = format!(
+            "{}/{}-{}-{}.bin",
+            dir,
+            self.name,
+            segment.first_retained_index(),
+            segment.current_change_count()
+        );
+        let result = std::fs::create_dir_all(dir)
+            .map_err(|e| e.into())
+            .and_then(|_| crate::world::persistence::save_history(segment, &path));
+        if let Err(e) = result {
+            eprintln!("Failed to archive pruned history to {}: {}", path, e);
+        }
+    }
+
+    /// Make the changes logged since the last sync durable. The fsync runs on
+    /// a separate handle, so painting carries on while it waits for the disk.
+    pub async fn sync_log(&self) -> std::io::Result<()> {
+        let Some(syncer) = self.world.read().await.log_syncer() else {
+            return Ok(());
+        };
+        tokio::task::spawn_blocking(move || syncer.sync()).await?
+    }
+}
+
+/// All rooms hosted by the server, keyed by name
+pub struct RoomRegistry {
+    rooms: RwLock<HashMap<String, Arc<Room>>>,
+}
+
+impl RoomRegistry {
+    /// Load the default room and every room found in the rooms directory.
+    /// Fails if any existing history or log file cannot be read, rather than
+    /// replacing it with a blank canvas.
+    pub fn load() -> Result<Self, Box<dyn std::error::Error>> {
+        let mut rooms = HashMap::new();
+        rooms.insert(DEFAULT_ROOM.to_string(), Arc::new(Self::open_room(DEFAULT_ROOM)?));
+
+        if let Ok(entries) = std::fs::read_dir(crate::env::rooms_dir()) {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.extension().and_then(|ext| ext.to_str()) != Some("bin") {
+                    continue;
+                }
+                let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                    continue;
+                };
+                if name == DEFAULT_ROOM || validate_room_name(name).is_err() {
+                    continue;
+                }
+                rooms.insert(name.to_string(), Arc::new(Self::open_room(name)?));
+            }
+        }
+
+        println!("Loaded {} room(s)", rooms.len());
+        Ok(Self {
+            rooms: RwLock::new(rooms),
+        })
+    }
+
+    /// Load a room's history from disk, or start a blank canvas if it has none,
+    /// and replay its write-ahead log on top
+    fn open_room(name: &str) -> Result<Room, Box<dyn std::error::Error>> {
+        let path = room_persistence_path(name);
+        let history = if Path::new(&path).exists() {
+            let history = crate::world::persistence::load_history(&path)
+                .map_err(|e| format!("Failed to load history for room '{}' from {}: {}", name, path, e))?;
+            println!("Loaded history for room '{}' from disk", name);
+            history
+        } else {
+            println!("No history found for room '{}', creating new world", name);
+            Self::blank_history(
+                crate::env::default_canvas_width(),
+                crate::env::default_canvas_height(),
+            ).expect("Failed to create canvas")
+        };
+
+        let archived = Path::new(&archive_marker_path(name)).exists();
+        Ok(Room::new(name, Self::open_world(name, history)?, archived))
+    }
+
+    /// Build a room's world and attach its write-ahead log
+    fn open_world(name: &str, history: History) -> Result<World, Box<dyn std::error::Error>> {
+        let mut world = World::from(history);
+        let log_path = wal::log_path(&room_persistence_path(name));
+        let (log, records) = WriteAheadLog::open(&log_path)
+            .map_err(|e| format!("Failed to open write-ahead log for room '{}': {}", name, e))?;
+        let replayed = world.attach_log(log, records);
+        if replayed > 0 {
+            println!("Replayed {} logged change(s) for room '{}'", replayed, name);
+        }
+        Ok(world)
+    }
+
+    fn blank_history(width: usize, height: usize) -> Result<History, RequestError> {
+        let canvas = Canvas::new(width, height)?;
+        Ok(History::new(
+            crate::env::default_snapshot_interval(),
+            crate::env::snapshot_keyframe_interval(),
+            &canvas,
+        ))
+    }
+
+    pub async fn get(&self, name: &str) -> Option<Arc<Room>> {
+        self.rooms.read().await.get(name).cloned()
+    }
+
+    pub async fn all(&self) -> Vec<Arc<Room>> {
+        self.rooms.read().await.values().cloned().collect()
+    }
+
+    /// Create a new, empty room and persist it immediately. Paints are restricted
+    /// to `allowed_colors` if given, and `cooldowns` override the default per role.
+    pub async fn create(
+        &self,
+        name: &str,
+        width: usize,
+        height: usize,
+        allowed_colors: Option<Vec<Color>>,
+        cooldowns: HashMap<Role, u64>,
+    ) -> Result<Arc<Room>, RequestError> {
+        validate_room_name(name)?;
+        check_dimensions(width, height)?;
+        let mut history = Self::blank_history(width, height)?;
+        history.set_allowed_colors(allowed_colors);
+        for (role, cooldown) in cooldowns {
+            history.set_cooldown(role.name(), Some(cooldown));
+        }
+
+        let room = {
+            let mut rooms = self.rooms.write().await;
+            if rooms.contains_key(name) {
+                return Err(RequestError::new(ErrorCode::RoomExists, format!("Room '{}' already exists", name)));
+            }
+            if let Err(e) = std::fs::create_dir_all(crate::env::rooms_dir()) {
+                eprintln!("Failed to create rooms directory: {}", e);
+            }
+            // A log left behind by an earlier room of the same name must not be replayed
+            let _ = std::fs::remove_file(wal::log_path(&room_persistence_path(name)));
+            let world = Self::open_world(name, history).map_err(|e| {
+                eprintln!("{}", e);
+                RequestError::new(ErrorCode::Internal, format!("Failed to create room '{}'", name))
+            })?;
+            let room = Arc::new(Room::new(name, world, false));
+            rooms.insert(name.to_string(), room.clone());
+            room
+        };
+
+        if let Err(e) = room.save(true).await {
+            eprintln!("Failed to save new room '{}': {}", name, e);
+        }
+        Ok(room)
+    }
+
+    /// Mark a room read-only; its history is kept on disk
+    pub async fn archive(&self, name: &str) -> Result<(), RequestError> {
+        let room = self.get_managed(name).await?;
+        room.archived.store(true, Ordering::Relaxed);
+
+        if let Err(e) = room.save(false).await {
+            eprintln!("Failed to save archived room '{}': {}", name, e);
+        }
+        if let Err(e) = std::fs::write(archive_marker_path(name), b"") {
+            eprintln!("Failed to write archive marker for room '{}': {}", name, e);
+        }
+        Ok(())
+    }
+
+    /// Remove a room and its files, returning it so its clients can be disconnected
+    pub async fn delete(&self, name: &str) -> Result<Arc<Room>, RequestError> {
+        self.get_managed(name).await?;
+        let room = self.rooms.write().await.remove(name).ok_or_else(|| room_not_found(name))?;
+
+        // Clients and saves that still hold the room check the flag under the
+        // world lock, so none can join or write the files back once it is set
+        let world_lock = room.world.write().await;
+        room.deleted.store(true, Ordering::Relaxed);
+        let path = room_persistence_path(name);
+        for file in [path.clone(), format!("{}.bak", path), wal::log_path(&path), archive_marker_path(name)] {
+            if Path::new(&file).exists()
+                && let Err(e) = std::fs::remove_file(&file)
+            {
+                eprintln!("Failed to remove {}: {}", file, e);
+            }
+        }
+        drop(world_lock);
+        Ok(room)
+    }
+
+    /// Look up a room that admins may archive or delete
+    async fn get_managed(&self, name: &str) -> Result<Arc<Room>, RequestError> {
+        if name == DEFAULT_ROOM {
+            return Err(RequestError::new(ErrorCode::Forbidden, "The default room cannot be archived or deleted"));
+        }
+        self.get(name).await.ok_or_else(|| room_not_found(name))
+    }
+}
+
+pub fn room_not_found(name: &str) -> RequestError {
+    RequestError::new(ErrorCode::RoomNotFound, format!("Room '{}' does not exist", name))
+}
+
+/// Reject canvas sizes that are empty or larger than `MAX_CANVAS_PIXELS`
+pub fn check_dimensions(width: usize, height: usize) -> Result<(), RequestError> {
+    let fits = width
+        .checked_mul(height)
+        .is_some_and(|pixels| pixels > 0 && pixels <= crate::env::max_canvas_pixels());
+    if fits {
+        Ok(())
+    } else {
+        Err(RequestError::new(
+            ErrorCode::InvalidDimensions,
+            format!(
+                "Invalid canvas dimensions {}x{}: both must be positive and hold at most {} pixels",
+                width,
+                height,
+                crate::env::max_canvas_pixels()
+            ),
+        ))
+    }
+}
+
+/// Room names double as file names, so only allow a conservative character set
+fn validate_room_name(name: &str) -> Result<(), RequestError> {
+    let valid = !name.is_empty()
+        && name.len() <= 64
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(RequestError::new(
+            ErrorCode::InvalidRoomName,
+            "Room names must be 1-64 characters of letters, digits, '-' or '_'",
+        ))
+    }
+}
+
+fn retention_policy() -> RetentionPolicy {
+    RetentionPolicy {
+        max_changes: crate::env::retain_changes(),
+        max_age_ms: crate::env::retain_days().map(|days| (days * 24.0 * 60.0 * 60.0 * 1000.0) as u64),
+    }
+}
+
+/// The default room keeps using `PERSISTENCE_PATH`; other rooms live in `ROOMS_DIR`
+fn room_persistence_path(name: &str) -> String {
+    if name == DEFAULT_ROOM {
+        crate::env::persistence_path().to_string()
+    } else {
+        format!("{}/{}.bin", crate::env::rooms_dir(), name)
+    }
+}
+
+fn archive_marker_path(name: &str) -> String {
+    format!("{}/{}.archived", crate::env::rooms_dir(), name)
+}
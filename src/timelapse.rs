@@ -0,0 +1,266 @@
+use crate::world::canvas::Canvas;
+use crate::world::change::Region;
+use crate::world::history::History;
+use crate::world::image::canvas_rgb;
+use crate::world::{wal, World};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+const USAGE: &str = "\
+Usage: Pixie timelapse <history file> <output> [options]
+
+Output:
+  <name>.gif                 animated GIF
+  <name>.png                 animated PNG (APNG)
+  anything else              directory of numbered PNG frames
+
+Options:
+  --every-changes <N>        one frame every N changes (default 100)
+  --every-seconds <S>        one frame every S seconds of wall-clock history time
+  --crop <x,y,width,height>  only render this region
+  --scale <K>                scale each pixel up to a KxK block (default 1)
+  --delay-ms <MS>            delay between animation frames (default 100)";
+
+/// How frames are spaced along the history
+enum Interval {
+    Changes(usize),
+    Millis(u64),
+}
+
+struct Options {
+    history_path: String,
+    output: String,
+    interval: Interval,
+    crop: Option<Region>,
+    scale: usize,
+    delay_ms: u16,
+}
+
+/// Entry point for `Pixie timelapse ...`; `args` excludes the subcommand itself
+pub fn run(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let options = parse_args(args).map_err(|e| format!("{}\n\n{}", e, USAGE))?;
+    // Include changes still waiting in the write-ahead log next to the history file
+    let mut world = World::from(crate::world::persistence::load_history(&options.history_path)?);
+    world.replay_log(wal::read_log(&wal::log_path(&options.history_path))?);
+    let history = world.history;
+
+    let frame_counts = frame_schedule(&history, &options.interval);
+    let (width, height) = match options.crop {
+        Some(crop) => (crop.width, crop.height),
+        None => {
+            let canvas = history.reconstruct_canvas();
+            (canvas.width(), canvas.height())
+        }
+    };
+    let (out_width, out_height) = (width * options.scale, height * options.scale);
+    println!(
+        "Rendering {} frame(s) of {}x{} from {} change(s)",
+        frame_counts.len(),
+        out_width,
+        out_height,
+        history.retained_changes().len()
+    );
+
+    let mut writer = FrameWriter::create(&options, out_width, out_height, frame_counts.len())?;
+    // Pruned changes can't be replayed, so the timelapse starts where retained history does
+    let mut applied = history.first_retained_index();
+    let mut canvas = history.canvas_at(applied);
+    for &count in &frame_counts {
+        history.replay(&mut canvas, applied..count);
+        applied = count;
+        writer.write(&render_frame(&canvas, options.crop, width, height, options.scale))?;
+    }
+    writer.finish()?;
+
+    println!("Timelapse written to {}", options.output);
+    Ok(())
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let [history_path, output, rest @ ..] = args else {
+        return Err("Missing history file or output".to_string());
+    };
+
+    let mut options = Options {
+        history_path: history_path.clone(),
+        output: output.clone(),
+        interval: Interval::Changes(100),
+        crop: None,
+        scale: 1,
+        delay_ms: 100,
+    };
+
+    let mut rest = rest.iter();
+    while let Some(flag) = rest.next() {
+        let value = rest.next().ok_or_else(|| format!("Missing value for {}", flag))?;
+        let invalid = || format!("Invalid value for {}: {}", flag, value);
+        match flag.as_str() {
+            "--every-changes" => {
+                let n = value.parse().ok().filter(|&n| n > 0).ok_or_else(invalid)?;
+                options.interval = Interval::Changes(n);
+            }
+            "--every-seconds" => {
+                let seconds: f64 = value.parse().ok().filter(|&s| s > 0.0).ok_or_else(invalid)?;
+                options.interval = Interval::Millis(((seconds * 1000.0) as u64).max(1));
+            }
+            "--crop" => {
+                let parts: Vec<usize> = value
+                    .split(',')
+                    .map(|part| part.trim().parse())
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| invalid())?;
+                let [x, y, width, height] = parts[..] else {
+                    return Err(invalid());
+                };
+                if width == 0 || height == 0 {
+                    return Err(invalid());
+                }
+                options.crop = Some(Region { x, y, width, height });
+            }
+            "--scale" => options.scale = value.parse().ok().filter(|&k| k > 0).ok_or_else(invalid)?,
+            "--delay-ms" => options.delay_ms = value.parse().map_err(|_| invalid())?,
+            _ => return Err(format!("Unknown option {}", flag)),
+        }
+    }
+
+    Ok(options)
+}
+
+/// Change counts at which to capture a frame, always including the start of the
+/// retained history and the end
+fn frame_schedule(history: &History, interval: &Interval) -> Vec<usize> {
+    let first = history.first_retained_index();
+    let total = history.current_change_count();
+    let mut counts = vec![first];
+
+    match *interval {
+        Interval::Changes(step) => counts.extend((first + step..total).step_by(step)),
+        Interval::Millis(step) => {
+            if let Some(first_change) = history.retained_changes().first() {
+                let mut boundary = first_change.timestamp + step;
+                for (index, change) in history.retained_changes().iter().enumerate() {
+                    // Capture the state just before the first change past each boundary
+                    if change.timestamp >= boundary {
+                        counts.push(first + index);
+                        boundary += (change.timestamp - boundary) / step * step + step;
+                    }
+                }
+            }
+        }
+    }
+
+    counts.push(total);
+    counts.dedup();
+    counts
+}
+
+/// Render a frame of fixed size, cropping or padding with white as the canvas changes size
+fn render_frame(canvas: &Canvas, crop: Option<Region>, width: usize, height: usize, scale: usize) -> Vec<u8> {
+    let rgb = canvas_rgb(canvas);
+    let (origin_x, origin_y) = crop.map_or((0, 0), |crop| (crop.x, crop.y));
+    let out_width = width * scale;
+
+    let mut frame = vec![0xFF; out_width * height * scale * 3];
+    for y in 0..height {
+        for x in 0..width {
+            let (cx, cy) = (origin_x + x, origin_y + y);
+            if cx >= canvas.width() || cy >= canvas.height() {
+                continue;
+            }
+            let source = (cy * canvas.width() + cx) * 3;
+            let pixel = &rgb[source..source + 3];
+            for dy in 0..scale {
+                let row = (y * scale + dy) * out_width;
+                for dx in 0..scale {
+                    let target = (row + x * scale + dx) * 3;
+                    frame[target..target + 3].copy_from_slice(pixel);
+                }
+            }
+        }
+    }
+    frame
+}
+
+/// Writes RGB frames to one of the supported output formats
+enum FrameWriter {
+    Gif {
+        encoder: gif::Encoder<BufWriter<File>>,
+        width: u16,
+        height: u16,
+        delay_cs: u16,
+    },
+    Apng(png::Writer<BufWriter<File>>),
+    Directory {
+        path: String,
+        width: u32,
+        height: u32,
+        next: usize,
+    },
+}
+
+impl FrameWriter {
+    fn create(options: &Options, width: usize, height: usize, frames: usize) -> Result<Self, Box<dyn std::error::Error>> {
+        let extension = Path::new(&options.output).extension().and_then(|ext| ext.to_str());
+        match extension {
+            Some("gif") => {
+                let (width, height) = (u16::try_from(width)?, u16::try_from(height)?);
+                let file = BufWriter::new(File::create(&options.output)?);
+                let mut encoder = gif::Encoder::new(file, width, height, &[])?;
+                encoder.set_repeat(gif::Repeat::Infinite)?;
+                Ok(FrameWriter::Gif {
+                    encoder,
+                    width,
+                    height,
+                    // GIF delays are in hundredths of a second
+                    delay_cs: options.delay_ms / 10,
+                })
+            }
+            Some("png") => {
+                let file = BufWriter::new(File::create(&options.output)?);
+                let mut encoder = png::Encoder::new(file, width as u32, height as u32);
+                encoder.set_color(png::ColorType::Rgb);
+                encoder.set_depth(png::BitDepth::Eight);
+                encoder.set_animated(frames as u32, 0)?;
+                encoder.set_frame_delay(options.delay_ms, 1000)?;
+                Ok(FrameWriter::Apng(encoder.write_header()?))
+            }
+            _ => {
+                std::fs::create_dir_all(&options.output)?;
+                Ok(FrameWriter::Directory {
+                    path: options.output.clone(),
+                    width: width as u32,
+                    height: height as u32,
+                    next: 0,
+                })
+            }
+        }
+    }
+
+    fn write(&mut self, rgb: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            FrameWriter::Gif { encoder, width, height, delay_cs } => {
+                let mut frame = gif::Frame::from_rgb_speed(*width, *height, rgb, 10);
+                frame.delay = *delay_cs;
+                encoder.write_frame(&frame)?;
+            }
+            FrameWriter::Apng(writer) => writer.write_image_data(rgb)?,
+            FrameWriter::Directory { path, width, height, next } => {
+                let file = BufWriter::new(File::create(format!("{}/frame_{:05}.png", path, next))?);
+                let mut encoder = png::Encoder::new(file, *width, *height);
+                encoder.set_color(png::ColorType::Rgb);
+                encoder.set_depth(png::BitDepth::Eight);
+                encoder.write_header()?.write_image_data(rgb)?;
+                *next += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), Box<dyn std::error::Error>> {
+        if let FrameWriter::Apng(writer) = self {
+            writer.finish()?;
+        }
+        Ok(())
+    }
+}
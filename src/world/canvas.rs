@@ -6,7 +6,6 @@ use std::sync::Arc;
 use std::sync::RwLock;
 
 
-#[allow(dead_code)]
 #[derive(Debug)]
 pub enum CanvasError {
     OutOfBounds { width: usize, height: usize },
@@ -27,9 +26,7 @@ pub struct Canvas {
 impl Canvas {
     /// Create a new canvas with the given width and height, initialized to white
     pub fn new(width: usize, height: usize) -> Result<Self, CanvasError> {
-        if width == 0 || height == 0 {
-            return Err(CanvasError::InvalidDimensions { width, height });
-        }
+        let len = Self::pixel_count(width, height)?;
 
         let palette = Arc::new(RwLock::new(Palette::new()));
         // White is always index 0 in new palette
@@ -37,7 +34,7 @@ impl Canvas {
         Ok(Self {
             width,
             height,
-            pixels: vec![0; width * height],  // 0 = white
+            pixels: vec![0; len],  // 0 = white
             palette,
         })
     }
@@ -45,18 +42,38 @@ impl Canvas {
     #[allow(dead_code)]
     /// Create canvas with existing palette
     pub fn with_palette(width: usize, height: usize, palette: Arc<RwLock<Palette>>) -> Result<Self, CanvasError> {
-        if width == 0 || height == 0 {
+        let len = Self::pixel_count(width, height)?;
+        
+        Ok(Self {
+            width,
+            height,
+            pixels: vec![0; len],
+            palette,
+        })
+    }
+
+    /// Create a canvas from row-major palette indices into `palette`
+    pub fn from_pixels(width: usize, height: usize, pixels: Vec<u32>, palette: Arc<RwLock<Palette>>) -> Result<Self, CanvasError> {
+        if pixels.len() != Self::pixel_count(width, height)? {
             return Err(CanvasError::InvalidDimensions { width, height });
         }
-        
+
         Ok(Self {
             width,
             height,
-            pixels: vec![0; width * height],
+            pixels,
             palette,
         })
     }
 
+    /// Number of pixels in a `width` x `height` canvas, rejecting empty and overflowing sizes
+    fn pixel_count(width: usize, height: usize) -> Result<usize, CanvasError> {
+        width
+            .checked_mul(height)
+            .filter(|&len| len > 0)
+            .ok_or(CanvasError::InvalidDimensions { width, height })
+    }
+
     /// Get the width of the canvas
     pub fn width(&self) -> usize {
         self.width
@@ -77,15 +94,35 @@ impl Canvas {
         }
 
         let index = y * self.width + x;
-        let color_index = {
-            let mut palette = self.palette.write().unwrap();
-            palette.add_color(color.to_hex().to_string())
-        };
+        // Most paints reuse a known color, which only needs the read lock
+        let known = self.palette.read().unwrap().get_index(color);
+        let color_index = known.unwrap_or_else(|| self.palette.write().unwrap().add_color(color));
         self.pixels[index] = color_index;
         Ok(())
     }
 
-    #[allow(dead_code)]
+    /// Paint a row-major block of pixels `width` wide with its top-left corner at (x, y).
+    /// `None` entries and pixels falling outside the canvas are skipped.
+    pub fn stamp(&mut self, x: usize, y: usize, width: usize, pixels: &[Option<Color>]) -> Result<(), CanvasError> {
+        if x >= self.width || y >= self.height {
+            return Err(CanvasError::OutOfBounds {
+                width: self.width,
+                height: self.height,
+            });
+        }
+
+        for (offset, color) in pixels.iter().enumerate() {
+            let (px, py) = (x + offset % width, y + offset / width);
+            if let Some(color) = color
+                && px < self.width
+                && py < self.height
+            {
+                self.set_pixel(px, py, *color)?;
+            }
+        }
+        Ok(())
+    }
+
     /// Get the color of the pixel at (x, y)
     pub fn get_pixel(&self, x: usize, y: usize) -> Result<Color, CanvasError> {
         if x >= self.width || y >= self.height {
@@ -98,30 +135,61 @@ impl Canvas {
         let index = y * self.width + x;
         let color_index = self.pixels[index];
         let palette = self.palette.read().unwrap();
-        let hex = palette.get_color(color_index).unwrap_or("#FFFFFF");
-        Color::from_hex(hex).map_err(|_| CanvasError::OutOfBounds { width: self.width, height: self.height })
+        Ok(palette.get_color(color_index).unwrap_or(Color::WHITE))
     }
 
     /// Get direct access to the pixels slice (row-major order of palette indices)
     pub fn pixels(&self) -> &[u32] {
         &self.pixels
     }
+
+    /// Pixels that differ from `base`, as (pixel index, palette index) pairs.
+    /// Returns `None` if the canvases have different dimensions.
+    pub fn diff(&self, base: &Canvas) -> Option<Vec<(u32, u32)>> {
+        if (self.width, self.height) != (base.width, base.height) {
+            return None;
+        }
+
+        let diff = self
+            .pixels
+            .iter()
+            .zip(&base.pixels)
+            .enumerate()
+            .filter(|(_, (pixel, base_pixel))| pixel != base_pixel)
+            .map(|(index, (&pixel, _))| (index as u32, pixel))
+            .collect();
+        Some(diff)
+    }
+
+    /// Overwrite pixels with a diff produced by `diff` against this canvas
+    pub fn apply_diff(&mut self, diff: &[(u32, u32)]) {
+        for &(index, pixel) in diff {
+            self.pixels[index as usize] = pixel;
+        }
+    }
     
+    /// Point the pixels at a new palette, translating each index through `mapping`
+    pub fn remap_palette(&mut self, mapping: &[u32], palette: Arc<RwLock<Palette>>) {
+        for pixel in &mut self.pixels {
+            *pixel = mapping[*pixel as usize];
+        }
+        self.palette = palette;
+    }
+
     /// Get the palette
     pub fn palette(&self) -> Arc<RwLock<Palette>> {
         self.palette.clone()
     }
 
+    /// Replace the palette the pixel indices refer to
+    pub fn set_palette(&mut self, palette: Arc<RwLock<Palette>>) {
+        self.palette = palette;
+    }
+
     /// Resize the canvas to new dimensions, anchoring the existing content
     pub fn resize(&mut self, new_width: usize, new_height: usize, anchor: ResizeAnchor) -> Result<(), CanvasError> {
-        if new_width == 0 || new_height == 0 {
-            return Err(CanvasError::InvalidDimensions {
-                width: new_width,
-                height: new_height,
-            });
-        }
-
-        let mut new_pixels = vec![0u32; new_width * new_height];  // 0 = white/default
+        let len = Self::pixel_count(new_width, new_height)?;
+        let mut new_pixels = vec![0u32; len];  // 0 = white/default
 
         let (offset_x, offset_y) = match anchor {
             ResizeAnchor::TopLeft => (0, 0),
@@ -10,7 +10,7 @@ pub enum ResizeAnchor {
     Center,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub enum ChangeEvent {
     Paint {
         x: usize,
@@ -22,10 +22,103 @@ pub enum ChangeEvent {
         width: usize,
         height: usize,
     },
+    /// Restores the canvas to how it was right after change `target_index`
+    Revert {
+        target_index: usize,
+    },
+    /// Sets a batch of pixels at once, e.g. when selectively reverting paints
+    Restore {
+        pixels: Vec<PixelPaint>,
+    },
+    /// Stamps an imported image with its top-left corner at (x, y).
+    /// `pixels` is row-major and `width` wide; `None` leaves a pixel untouched.
+    Import {
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        pixels: Vec<Option<Color>>,
+    },
+    /// Restricts paints to `colors`, or lifts the restriction if `None`.
+    /// Leaves the canvas untouched.
+    SetPalette {
+        colors: Option<Vec<Color>>,
+    },
+}
+
+impl ChangeEvent {
+    /// The color this change wrote at (x, y), if it wrote that pixel directly
+    pub fn color_at(&self, x: usize, y: usize) -> Option<&Color> {
+        match self {
+            ChangeEvent::Paint { x: px, y: py, color } if *px == x && *py == y => Some(color),
+            ChangeEvent::Restore { pixels } => pixels.iter().find(|p| p.x == x && p.y == y).map(|p| &p.color),
+            ChangeEvent::Import { x: ix, y: iy, width, height, pixels } => {
+                let region = Region { x: *ix, y: *iy, width: *width, height: *height };
+                if !region.contains(x, y) {
+                    return None;
+                }
+                pixels.get((y - iy) * width + (x - ix))?.as_ref()
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PixelPaint {
+    pub x: usize,
+    pub y: usize,
+    pub color: Color,
+}
+
+/// A rectangle of pixels; `x`/`y` is the top-left corner
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Region {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Region {
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        x >= self.x && y >= self.y && x - self.x < self.width && y - self.y < self.height
+    }
+}
+
+/// Selects paint changes by author, region and time window; unset criteria match anything
+#[derive(Debug, Clone, Default)]
+pub struct PaintFilter {
+    pub author: Option<String>,
+    pub region: Option<Region>,
+    /// Inclusive lower bound on the timestamp in milliseconds
+    pub from: Option<u64>,
+    /// Inclusive upper bound on the timestamp in milliseconds
+    pub to: Option<u64>,
+}
+
+impl PaintFilter {
+    /// Whether no criteria are set, i.e. the filter would match every paint
+    pub fn is_empty(&self) -> bool {
+        self.author.is_none() && self.region.is_none() && self.from.is_none() && self.to.is_none()
+    }
+
+    /// Whether the change is a single-pixel paint matching every set criterion
+    pub fn matches(&self, change: &Change) -> bool {
+        let ChangeEvent::Paint { x, y, .. } = &change.event else {
+            return false;
+        };
+        self.author.as_ref().is_none_or(|author| change.author.as_ref() == Some(author))
+            && self.region.is_none_or(|region| region.contains(*x, *y))
+            && self.from.is_none_or(|from| change.timestamp >= from)
+            && self.to.is_none_or(|to| change.timestamp <= to)
+    }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Change {
     pub event: ChangeEvent,
     pub timestamp: u64,
+    /// Identity (user id or session) of whoever made the change
+    pub author: Option<String>,
 }
@@ -1,32 +1,387 @@
 use serde::{Serialize, Deserialize};
 
 
-#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
-pub struct Color {
-    hex: String,
-}
+/// An RGBA color packed as 0xRRGGBBAA. Strings are only parsed and formatted
+/// at the edges (protocol messages and legacy files).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Color(u32);
 
 
 impl Color {
+    /// Opaque white, the color of a blank canvas
+    pub const WHITE: Color = Color::from_rgb([0xFF, 0xFF, 0xFF]);
+
+    /// Fully transparent; painting it erases a pixel back to `WHITE`
+    pub const TRANSPARENT: Color = Color(0);
+
+    /// An opaque color from its red, green and blue components
+    pub const fn from_rgb([r, g, b]: [u8; 3]) -> Self {
+        Self::from_rgba([r, g, b, 0xFF])
+    }
+
+    /// A color from its red, green, blue and alpha components.
+    /// Every fully transparent color is the same color.
+    pub const fn from_rgba([r, g, b, a]: [u8; 4]) -> Self {
+        if a == 0 {
+            return Self::TRANSPARENT;
+        }
+        Color(u32::from_be_bytes([r, g, b, a]))
+    }
 
+    /// Parse `#RGB`, `#RGBA`, `#RRGGBB` or `#RRGGBBAA`; the `#` is optional
     pub fn from_hex(hex: &str) -> Result<Self, String> {
         let hex = hex.trim_start_matches('#');
-        
-        if hex.len() != 6 {
-            return Err(format!("Invalid hex color length: expected 6 characters, got {}", hex.len()));
+        if !hex.is_ascii() {
+            return Err(format!("Invalid hex color: {}", hex));
+        }
+
+        let digits: Vec<u8> = match hex.len() {
+            // Short forms repeat each digit, so #F80 is #FF8800
+            3 | 4 => hex.chars().map(|c| parse_hex_byte(&format!("{0}{0}", c))).collect::<Result<_, _>>()?,
+            6 | 8 => (0..hex.len()).step_by(2).map(|i| parse_hex_byte(&hex[i..i + 2])).collect::<Result<_, _>>()?,
+            len => return Err(format!("Invalid hex color length: expected 3, 4, 6 or 8 digits, got {}", len)),
+        };
+
+        match digits[..] {
+            [r, g, b] => Ok(Self::from_rgb([r, g, b])),
+            [r, g, b, a] => Ok(Self::from_rgba([r, g, b, a])),
+            _ => unreachable!("Hex colors have 3 or 4 components"),
+        }
+    }
+
+    /// Parse any supported color syntax: hex, `rgb()`/`rgba()`, a CSS color name or `transparent`
+    pub fn parse(input: &str) -> Result<Self, String> {
+        let input = input.trim();
+        let lower = input.to_ascii_lowercase();
+
+        if input.starts_with('#') {
+            Self::from_hex(input)
+        } else if let Some(args) = lower.strip_prefix("rgba(").or_else(|| lower.strip_prefix("rgb(")) {
+            let args = args.strip_suffix(')').ok_or_else(|| format!("Missing ')' in color: {}", input))?;
+            Self::from_functional(args).map_err(|e| format!("Invalid color {}: {}", input, e))
+        } else if lower == "transparent" {
+            Ok(Self::TRANSPARENT)
+        } else {
+            NAMED_COLORS
+                .binary_search_by_key(&lower.as_str(), |&(name, _)| name)
+                .map(|index| Self::from_rgb(NAMED_COLORS[index].1))
+                .map_err(|_| format!("Unknown color: {}", input))
+        }
+    }
+
+    /// Parse the arguments of `rgb()`/`rgba()`, either comma separated
+    /// (`255, 0, 0, 0.5`) or space separated with a slash before the alpha (`255 0 0 / 50%`)
+    fn from_functional(args: &str) -> Result<Self, String> {
+        let parts: Vec<&str> = if args.contains(',') {
+            args.split(',').map(str::trim).collect()
+        } else {
+            let (channels, alpha) = match args.split_once('/') {
+                Some((channels, alpha)) => (channels, Some(alpha.trim())),
+                None => (args, None),
+            };
+            channels.split_whitespace().chain(alpha).collect()
+        };
+
+        let (channels, alpha) = match parts[..] {
+            [r, g, b] => ([r, g, b], None),
+            [r, g, b, a] => ([r, g, b], Some(a)),
+            _ => return Err(format!("expected 3 or 4 components, got {}", parts.len())),
+        };
+
+        let [r, g, b] = channels.map(|channel| match channel.strip_suffix('%') {
+            Some(percent) => parse_fraction(percent, 100.0),
+            None => parse_fraction(channel, 255.0),
+        });
+        let a = match alpha {
+            Some(alpha) => match alpha.strip_suffix('%') {
+                Some(percent) => parse_fraction(percent, 100.0)?,
+                None => parse_fraction(alpha, 1.0)?,
+            },
+            None => 0xFF,
+        };
+        Ok(Self::from_rgba([r?, g?, b?, a]))
+    }
+
+    /// Format as an uppercase `#RRGGBB` string, or `#RRGGBBAA` if not opaque
+    pub fn to_hex(self) -> String {
+        let [r, g, b, a] = self.0.to_be_bytes();
+        if a == 0xFF {
+            format!("#{:02X}{:02X}{:02X}", r, g, b)
+        } else {
+            format!("#{:02X}{:02X}{:02X}{:02X}", r, g, b, a)
+        }
+    }
+
+    /// Get the red, green and blue components
+    pub fn rgb(self) -> [u8; 3] {
+        let [r, g, b, _] = self.0.to_be_bytes();
+        [r, g, b]
+    }
+
+    pub fn alpha(self) -> u8 {
+        self.0.to_be_bytes()[3]
+    }
+
+    pub fn is_opaque(self) -> bool {
+        self.alpha() == 0xFF
+    }
+
+    /// The opaque color seen when this color is drawn over `below` ("source over")
+    pub fn over(self, below: Color) -> Color {
+        let alpha = self.alpha() as u32;
+        let [r, g, b] = std::array::from_fn(|i| {
+            let (top, bottom) = (self.rgb()[i] as u32, below.rgb()[i] as u32);
+            ((top * alpha + bottom * (0xFF - alpha) + 0x7F) / 0xFF) as u8
+        });
+        Color::from_rgb([r, g, b])
+    }
+
+    /// The color in `colors` closest to this one by RGB distance, or this
+    /// color itself if `colors` is empty
+    pub fn nearest(self, colors: &[Color]) -> Color {
+        let distance = |other: &Color| -> u32 {
+            self.rgb()
+                .iter()
+                .zip(other.rgb())
+                .map(|(&a, b)| (a as i32 - b as i32).pow(2) as u32)
+                .sum()
+        };
+        colors.iter().copied().min_by_key(distance).unwrap_or(self)
+    }
+}
+
+
+fn parse_hex_byte(pair: &str) -> Result<u8, String> {
+    u8::from_str_radix(pair, 16).map_err(|_| format!("Invalid hex digits: {}", pair))
+}
+
+/// Parse a number between 0 and `max`, scaled to 0..=255
+fn parse_fraction(value: &str, max: f64) -> Result<u8, String> {
+    let number: f64 = value.trim().parse().map_err(|_| format!("invalid number '{}'", value))?;
+    if !(0.0..=max).contains(&number) {
+        return Err(format!("{} is outside 0 to {}", value, max));
+    }
+    Ok((number / max * 255.0).round() as u8)
+}
+
+
+/// CSS named colors, sorted by name
+const NAMED_COLORS: &[(&str, [u8; 3])] = &[
+    ("aliceblue", [240, 248, 255]),
+    ("antiquewhite", [250, 235, 215]),
+    ("aqua", [0, 255, 255]),
+    ("aquamarine", [127, 255, 212]),
+    ("azure", [240, 255, 255]),
+    ("beige", [245, 245, 220]),
+    ("bisque", [255, 228, 196]),
+    ("black", [0, 0, 0]),
+    ("blanchedalmond", [255, 235, 205]),
+    ("blue", [0, 0, 255]),
+    ("blueviolet", [138, 43, 226]),
+    ("brown", [165, 42, 42]),
+    ("burlywood", [222, 184, 135]),
+    ("cadetblue", [95, 158, 160]),
+    ("chartreuse", [127, 255, 0]),
+    ("chocolate", [210, 105, 30]),
+    ("coral", [255, 127, 80]),
+    ("cornflowerblue", [100, 149, 237]),
+    ("cornsilk", [255, 248, 220]),
+    ("crimson", [220, 20, 60]),
+    ("cyan", [0, 255, 255]),
+    ("darkblue", [0, 0, 139]),
+    ("darkcyan", [0, 139, 139]),
+    ("darkgoldenrod", [184, 134, 11]),
+    ("darkgray", [169, 169, 169]),
+    ("darkgreen", [0, 100, 0]),
+    ("darkgrey", [169, 169, 169]),
+    ("darkkhaki", [189, 183, 107]),
+    ("darkmagenta", [139, 0, 139]),
+    ("darkolivegreen", [85, 107, 47]),
+    ("darkorange", [255, 140, 0]),
+    ("darkorchid", [153, 50, 204]),
+    ("darkred", [139, 0, 0]),
+    ("darksalmon", [233, 150, 122]),
+    ("darkseagreen", [143, 188, 143]),
+    ("darkslateblue", [72, 61, 139]),
+    ("darkslategray", [47, 79, 79]),
+    ("darkslategrey", [47, 79, 79]),
+    ("darkturquoise", [0, 206, 209]),
+    ("darkviolet", [148, 0, 211]),
+    ("deeppink", [255, 20, 147]),
+    ("deepskyblue", [0, 191, 255]),
+    ("dimgray", [105, 105, 105]),
+    ("dimgrey", [105, 105, 105]),
+    ("dodgerblue", [30, 144, 255]),
+    ("firebrick", [178, 34, 34]),
+    ("floralwhite", [255, 250, 240]),
+    ("forestgreen", [34, 139, 34]),
+    ("fuchsia", [255, 0, 255]),
+    ("gainsboro", [220, 220, 220]),
+    ("ghostwhite", [248, 248, 255]),
+    ("gold", [255, 215, 0]),
+    ("goldenrod", [218, 165, 32]),
+    ("gray", [128, 128, 128]),
+    ("green", [0, 128, 0]),
+    ("greenyellow", [173, 255, 47]),
+    ("grey", [128, 128, 128]),
+    ("honeydew", [240, 255, 240]),
+    ("hotpink", [255, 105, 180]),
+    ("indianred", [205, 92, 92]),
+    ("indigo", [75, 0, 130]),
+    ("ivory", [255, 255, 240]),
+    ("khaki", [240, 230, 140]),
+    ("lavender", [230, 230, 250]),
+    ("lavenderblush", [255, 240, 245]),
+    ("lawngreen", [124, 252, 0]),
+    ("lemonchiffon", [255, 250, 205]),
+    ("lightblue", [173, 216, 230]),
+    ("lightcoral", [240, 128, 128]),
+    ("lightcyan", [224, 255, 255]),
+    ("lightgoldenrodyellow", [250, 250, 210]),
+    ("lightgray", [211, 211, 211]),
+    ("lightgreen", [144, 238, 144]),
+    ("lightgrey", [211, 211, 211]),
+    ("lightpink", [255, 182, 193]),
+    ("lightsalmon", [255, 160, 122]),
+    ("lightseagreen", [32, 178, 170]),
+    ("lightskyblue", [135, 206, 250]),
+    ("lightslategray", [119, 136, 153]),
+    ("lightslategrey", [119, 136, 153]),
+    ("lightsteelblue", [176, 196, 222]),
+    ("lightyellow", [255, 255, 224]),
+    ("lime", [0, 255, 0]),
+    ("limegreen", [50, 205, 50]),
+    ("linen", [250, 240, 230]),
+    ("magenta", [255, 0, 255]),
+    ("maroon", [128, 0, 0]),
+    ("mediumaquamarine", [102, 205, 170]),
+    ("mediumblue", [0, 0, 205]),
+    ("mediumorchid", [186, 85, 211]),
+    ("mediumpurple", [147, 112, 219]),
+    ("mediumseagreen", [60, 179, 113]),
+    ("mediumslateblue", [123, 104, 238]),
+    ("mediumspringgreen", [0, 250, 154]),
+    ("mediumturquoise", [72, 209, 204]),
+    ("mediumvioletred", [199, 21, 133]),
+    ("midnightblue", [25, 25, 112]),
+    ("mintcream", [245, 255, 250]),
+    ("mistyrose", [255, 228, 225]),
+    ("moccasin", [255, 228, 181]),
+    ("navajowhite", [255, 222, 173]),
+    ("navy", [0, 0, 128]),
+    ("oldlace", [253, 245, 230]),
+    ("olive", [128, 128, 0]),
+    ("olivedrab", [107, 142, 35]),
+    ("orange", [255, 165, 0]),
+    ("orangered", [255, 69, 0]),
+    ("orchid", [218, 112, 214]),
+    ("palegoldenrod", [238, 232, 170]),
+    ("palegreen", [152, 251, 152]),
+    ("paleturquoise", [175, 238, 238]),
+    ("palevioletred", [219, 112, 147]),
+    ("papayawhip", [255, 239, 213]),
+    ("peachpuff", [255, 218, 185]),
+    ("peru", [205, 133, 63]),
+    ("pink", [255, 192, 203]),
+    ("plum", [221, 160, 221]),
+    ("powderblue", [176, 224, 230]),
+    ("purple", [128, 0, 128]),
+    ("rebeccapurple", [102, 51, 153]),
+    ("red", [255, 0, 0]),
+    ("rosybrown", [188, 143, 143]),
+    ("royalblue", [65, 105, 225]),
+    ("saddlebrown", [139, 69, 19]),
+    ("salmon", [250, 128, 114]),
+    ("sandybrown", [244, 164, 96]),
+    ("seagreen", [46, 139, 87]),
+    ("seashell", [255, 245, 238]),
+    ("sienna", [160, 82, 45]),
+    ("silver", [192, 192, 192]),
+    ("skyblue", [135, 206, 235]),
+    ("slateblue", [106, 90, 205]),
+    ("slategray", [112, 128, 144]),
+    ("slategrey", [112, 128, 144]),
+    ("snow", [255, 250, 250]),
+    ("springgreen", [0, 255, 127]),
+    ("steelblue", [70, 130, 180]),
+    ("tan", [210, 180, 140]),
+    ("teal", [0, 128, 128]),
+    ("thistle", [216, 191, 216]),
+    ("tomato", [255, 99, 71]),
+    ("turquoise", [64, 224, 208]),
+    ("violet", [238, 130, 238]),
+    ("wheat", [245, 222, 179]),
+    ("white", [255, 255, 255]),
+    ("whitesmoke", [245, 245, 245]),
+    ("yellow", [255, 255, 0]),
+    ("yellowgreen", [154, 205, 50]),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_hex(input: &str) -> String {
+        Color::parse(input).unwrap().to_hex()
+    }
+
+    #[test]
+    fn hex_forms_expand_to_the_same_color() {
+        assert_eq!(parse_hex("#F80"), "#FF8800");
+        assert_eq!(parse_hex("#ff8800"), "#FF8800");
+        assert_eq!(parse_hex("#F808"), "#FF880088");
+        assert_eq!(parse_hex("#FF880080"), "#FF880080");
+        assert_eq!(Color::from_hex("F80").unwrap(), Color::from_rgb([0xFF, 0x88, 0x00]));
+        for invalid in ["#FF888", "#GGG", "#FF88000", "#", "#ÿÿÿ"] {
+            assert!(Color::parse(invalid).is_err(), "{} should not parse", invalid);
+        }
+    }
+
+    #[test]
+    fn functional_notation() {
+        assert_eq!(parse_hex("rgb(255, 87, 51)"), "#FF5733");
+        assert_eq!(parse_hex("RGB(255,87,51)"), "#FF5733");
+        assert_eq!(parse_hex("rgba(255, 87, 51, 0.5)"), "#FF573380");
+        assert_eq!(parse_hex("rgb(255 87 51 / 50%)"), "#FF573380");
+        assert_eq!(parse_hex("rgb(100%, 0%, 50%)"), "#FF0080");
+        assert_eq!(parse_hex("rgba(255, 87, 51, 1)"), "#FF5733");
+        for invalid in ["rgb(255, 87)", "rgb(256, 0, 0)", "rgba(0, 0, 0, 2)", "rgb(a, b, c)", "rgb(1, 2, 3"] {
+            assert!(Color::parse(invalid).is_err(), "{} should not parse", invalid);
         }
-        
-        // Validate by parsing (ensures valid hex)
-        u8::from_str_radix(&hex[0..2], 16).map_err(|e| format!("Invalid red component: {}", e))?;
-        u8::from_str_radix(&hex[2..4], 16).map_err(|e| format!("Invalid green component: {}", e))?;
-        u8::from_str_radix(&hex[4..6], 16).map_err(|e| format!("Invalid blue component: {}", e))?;
-        
-        Ok(Self {
-            hex: format!("#{}", hex.to_uppercase())
-        })
     }
 
-    pub fn to_hex(&self) -> &str {
-        &self.hex
+    #[test]
+    fn named_colors() {
+        assert_eq!(parse_hex("tomato"), "#FF6347");
+        assert_eq!(parse_hex(" White "), "#FFFFFF");
+        assert_eq!(Color::parse("transparent").unwrap(), Color::TRANSPARENT);
+        assert!(Color::parse("notacolor").is_err());
+        // Lookups binary-search the table
+        assert!(NAMED_COLORS.windows(2).all(|pair| pair[0].0 < pair[1].0));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn zero_alpha_is_always_transparent() {
+        assert_eq!(Color::from_rgba([12, 34, 56, 0]), Color::TRANSPARENT);
+        assert_eq!(Color::parse("#12345600").unwrap(), Color::TRANSPARENT);
+        assert_eq!(Color::parse("rgba(255, 0, 0, 0)").unwrap(), Color::TRANSPARENT);
+        assert_ne!(Color::from_rgba([0, 0, 0, 1]), Color::TRANSPARENT);
+    }
+
+    #[test]
+    fn translucent_colors_blend_over_the_pixel_below() {
+        let half_red = Color::parse("rgba(255, 0, 0, 0.5)").unwrap();
+        assert_eq!(half_red.over(Color::WHITE), Color::from_rgb([255, 127, 127]));
+        assert_eq!(Color::from_rgb([1, 2, 3]).over(Color::WHITE), Color::from_rgb([1, 2, 3]));
+        assert!(half_red.over(Color::WHITE).is_opaque());
+    }
+
+    #[test]
+    fn nearest_picks_the_closest_color() {
+        let allowed = [Color::WHITE, Color::from_rgb([0, 0, 0]), Color::from_rgb([229, 0, 0])];
+        assert_eq!(Color::from_rgb([200, 30, 10]).nearest(&allowed), allowed[2]);
+        assert_eq!(Color::from_rgb([40, 40, 40]).nearest(&allowed), allowed[1]);
+        assert_eq!(Color::from_rgb([0, 0, 0]).nearest(&allowed), allowed[1]);
+        assert_eq!(Color::from_rgb([1, 2, 3]).nearest(&[]), Color::from_rgb([1, 2, 3]));
+    }
+}
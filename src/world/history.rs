@@ -1,121 +1,741 @@
-use super::canvas::Canvas;
-use super::change::Change;
+use super::canvas::{Canvas, CanvasError};
+use super::change::{Change, ChangeEvent, PaintFilter, PixelPaint};
+use super::color::Color;
+use super::palette::Palette;
 use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::Range;
+use std::sync::{Arc, RwLock};
 
 
 #[derive(Debug)]
 pub enum RollbackError {
     IndexOutOfBounds {
-        #[allow(dead_code)]
         target: usize,
-        #[allow(dead_code)]
         max: usize,
     },
+    /// The target was pruned by the retention policy
+    Pruned {
+        target: usize,
+        earliest: usize,
+    },
+    NothingToUndo,
+    NothingToRedo,
 }
 
 
-#[derive(Serialize, Deserialize)]
+/// Default number of snapshots per keyframe; the snapshots in between store
+/// only the pixels that changed since the previous snapshot
+pub const DEFAULT_KEYFRAME_INTERVAL: usize = 16;
+
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Snapshot {
-    pub canvas: Canvas,
     pub change_count: usize,
+    data: SnapshotData,
+}
+
+
+#[derive(Clone, Serialize, Deserialize)]
+enum SnapshotData {
+    /// A full copy of the canvas
+    Keyframe(Canvas),
+    /// (pixel index, palette index) pairs that differ from the previous snapshot,
+    /// which always has the same dimensions
+    Delta(Vec<(u32, u32)>),
+}
+
+
+/// A copy of the snapshots and changes needed to rebuild a past canvas,
+/// taken by `History::reconstruction_at`
+pub struct Reconstruction {
+    /// From the keyframe up to the snapshot the changes start at
+    snapshots: Vec<Snapshot>,
+    /// Changes to replay on top, with no revert markers among them
+    changes: Vec<Change>,
+}
+
+
+impl Reconstruction {
+    pub fn into_canvas(self) -> Canvas {
+        let mut canvas = History::materialize(&self.snapshots);
+        for change in &self.changes {
+            apply_event(&mut canvas, &change.event);
+        }
+        canvas
+    }
+}
+
+
+impl From<Canvas> for Reconstruction {
+    /// A canvas that is already known, with nothing left to replay
+    fn from(canvas: Canvas) -> Self {
+        Reconstruction {
+            snapshots: vec![Snapshot::keyframe(canvas, 0)],
+            changes: Vec::new(),
+        }
+    }
+}
+
+
+/// Apply a change that doesn't depend on earlier history. Revert markers
+/// are left to the caller.
+fn apply_event(canvas: &mut Canvas, event: &ChangeEvent) {
+    match event {
+        ChangeEvent::Paint { x, y, color } => {
+            let _ = canvas.set_pixel(*x, *y, *color);
+        }
+        ChangeEvent::Resize { anchor, width, height } => {
+            let _ = canvas.resize(*width, *height, *anchor);
+        }
+        ChangeEvent::Restore { pixels } => {
+            for pixel in pixels {
+                let _ = canvas.set_pixel(pixel.x, pixel.y, pixel.color);
+            }
+        }
+        ChangeEvent::Import { x, y, width, pixels, .. } => {
+            let _ = canvas.stamp(*x, *y, *width, pixels);
+        }
+        ChangeEvent::Revert { .. } | ChangeEvent::SetPalette { .. } => {}
+    }
+}
+
+
+impl Snapshot {
+    /// A snapshot holding the full canvas
+    pub fn keyframe(canvas: Canvas, change_count: usize) -> Self {
+        Snapshot {
+            change_count,
+            data: SnapshotData::Keyframe(canvas),
+        }
+    }
+
+    /// A snapshot holding the pixels that differ from the previous snapshot
+    pub fn delta(diff: Vec<(u32, u32)>, change_count: usize) -> Self {
+        Snapshot {
+            change_count,
+            data: SnapshotData::Delta(diff),
+        }
+    }
+
+    pub fn is_keyframe(&self) -> bool {
+        matches!(self.data, SnapshotData::Keyframe(_))
+    }
+
+    /// Bytes used by the stored pixel data
+    pub fn stored_bytes(&self) -> usize {
+        match &self.data {
+            SnapshotData::Keyframe(canvas) => std::mem::size_of_val(canvas.pixels()),
+            SnapshotData::Delta(diff) => std::mem::size_of_val(diff.as_slice()),
+        }
+    }
+}
+
+
+/// How much of the history keeps full change detail. A change is pruned only
+/// once it falls outside every configured window; with none configured,
+/// nothing is pruned.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RetentionPolicy {
+    /// Keep at least this many of the most recent changes
+    pub max_changes: Option<usize>,
+    /// Keep changes made within this many milliseconds
+    pub max_age_ms: Option<u64>,
 }
 
 
 #[derive(Serialize, Deserialize)]
 pub struct History {
+    /// Snapshots ordered by `change_count`, starting with a keyframe. Those up
+    /// to `first_index` are all keyframes and survive pruning on a thinning
+    /// schedule or because a revert marker needs them.
     pub snapshots: Vec<Snapshot>,
-    pub changes: Vec<Change>,
+    /// Retained changes; `changes[0]` is change number `first_index`
+    changes: Vec<Change>,
     snapshot_interval: usize,
+    /// Maximum number of snapshots from one keyframe to the next
+    keyframe_interval: usize,
+    /// Indices of revert markers that can be undone, most recent last
+    undo_stack: Vec<usize>,
+    /// Indices of undone revert markers that can be redone, most recent last
+    redo_stack: Vec<usize>,
+    /// Number of changes pruned from the start of the history
+    first_index: usize,
+    /// The only colors paints may use, if the canvas has a restricted palette.
+    /// Set by the latest `SetPalette` change; rollbacks leave it alone.
+    allowed_colors: Option<Vec<Color>>,
+    /// Milliseconds between paints configured for this canvas, keyed by role name.
+    /// Roles without an entry use the server default.
+    cooldowns: BTreeMap<String, u64>,
+    /// Identities banned from changing this canvas, with the unix time in
+    /// milliseconds their ban ends, or `None` for a permanent ban
+    bans: BTreeMap<String, Option<u64>>,
 }
 
 
 #[allow(dead_code)]
 impl History {
-    /// Create a new history tracker with the specified snapshot interval
-    pub fn new(snapshot_interval: usize, initial_canvas: &Canvas) -> Self {
-        let initial_snapshot = Snapshot {
-            canvas: initial_canvas.clone(),
-            change_count: 0,
-        };
-        
+    /// Create a new history tracker with the specified snapshot and keyframe intervals
+    pub fn new(snapshot_interval: usize, keyframe_interval: usize, initial_canvas: &Canvas) -> Self {
         History {
             changes: Vec::new(),
-            snapshots: vec![initial_snapshot],
+            snapshots: vec![Snapshot::keyframe(initial_canvas.clone(), 0)],
             snapshot_interval,
+            keyframe_interval: keyframe_interval.max(1),
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
+            first_index: 0,
+            allowed_colors: None,
+            cooldowns: BTreeMap::new(),
+            bans: BTreeMap::new(),
         }
     }
 
+    /// Rebuild a history from stored parts, re-encoding its snapshots as keyframes and deltas
+    pub fn from_parts(
+        snapshots: Vec<Snapshot>,
+        changes: Vec<Change>,
+        snapshot_interval: usize,
+        keyframe_interval: usize,
+        undo_stack: Vec<usize>,
+        redo_stack: Vec<usize>,
+        first_index: usize,
+    ) -> Self {
+        let mut history = History {
+            snapshots: Vec::new(),
+            changes,
+            snapshot_interval,
+            keyframe_interval: keyframe_interval.max(1),
+            undo_stack,
+            redo_stack,
+            first_index,
+            allowed_colors: None,
+            cooldowns: BTreeMap::new(),
+            bans: BTreeMap::new(),
+        };
+        for (index, snapshot) in snapshots.iter().enumerate() {
+            let canvas = Self::materialize(&snapshots[..=index]);
+            if snapshot.change_count < first_index {
+                history.snapshots.push(Snapshot::keyframe(canvas, snapshot.change_count));
+            } else {
+                history.push_snapshot(&canvas, snapshot.change_count);
+            }
+        }
+        history
+    }
+
+    /// The palette shared by every canvas in this history
+    pub fn palette(&self) -> Arc<RwLock<Palette>> {
+        match &self.snapshots.first().expect("History must have at least one snapshot").data {
+            SnapshotData::Keyframe(canvas) => canvas.palette(),
+            SnapshotData::Delta(_) => unreachable!("The first snapshot is always a keyframe"),
+        }
+    }
+
+    /// Point every snapshot at `palette`, e.g. after loading them from disk
+    pub fn set_palette(&mut self, palette: Arc<RwLock<Palette>>) {
+        for snapshot in &mut self.snapshots {
+            if let SnapshotData::Keyframe(canvas) = &mut snapshot.data {
+                canvas.set_palette(palette.clone());
+            }
+        }
+    }
+
+    /// Flag every palette index that a snapshot still refers to
+    pub fn mark_used_colors(&self, used: &mut [bool]) {
+        for snapshot in &self.snapshots {
+            let indices: Box<dyn Iterator<Item = u32>> = match &snapshot.data {
+                SnapshotData::Keyframe(canvas) => Box::new(canvas.pixels().iter().copied()),
+                SnapshotData::Delta(diff) => Box::new(diff.iter().map(|&(_, pixel)| pixel)),
+            };
+            for index in indices {
+                used[index as usize] = true;
+            }
+        }
+    }
+
+    /// Point every snapshot at a new palette, translating their indices through `mapping`.
+    /// Changes store colors rather than indices, so replaying them is unaffected.
+    pub fn remap_palette(&mut self, mapping: &[u32], palette: Arc<RwLock<Palette>>) {
+        for snapshot in &mut self.snapshots {
+            match &mut snapshot.data {
+                SnapshotData::Keyframe(canvas) => canvas.remap_palette(mapping, palette.clone()),
+                SnapshotData::Delta(diff) => {
+                    for (_, pixel) in diff.iter_mut() {
+                        *pixel = mapping[*pixel as usize];
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reconstruct the canvas of the last of `snapshots` from the keyframe before it
+    fn materialize(snapshots: &[Snapshot]) -> Canvas {
+        let start = snapshots
+            .iter()
+            .rposition(Snapshot::is_keyframe)
+            .expect("The first snapshot is always a keyframe");
+        let SnapshotData::Keyframe(keyframe) = &snapshots[start].data else {
+            unreachable!();
+        };
+
+        let mut canvas = keyframe.clone();
+        for snapshot in &snapshots[start + 1..] {
+            if let SnapshotData::Delta(diff) = &snapshot.data {
+                canvas.apply_diff(diff);
+            }
+        }
+        canvas
+    }
+
+    /// Reconstruct the canvas stored by the snapshot at `index`
+    fn snapshot_canvas(&self, index: usize) -> Canvas {
+        Self::materialize(&self.snapshots[..=index])
+    }
+
+    /// Append a snapshot of `canvas`, as a delta against the previous snapshot
+    /// unless a keyframe is due or the dimensions changed
+    fn push_snapshot(&mut self, canvas: &Canvas, change_count: usize) {
+        let since_keyframe = self.snapshots.iter().rev().take_while(|s| !s.is_keyframe()).count() + 1;
+        let diff = match self.snapshots.len().checked_sub(1) {
+            Some(last) if since_keyframe < self.keyframe_interval => canvas.diff(&self.snapshot_canvas(last)),
+            _ => None,
+        };
+
+        self.snapshots.push(match diff {
+            Some(diff) => Snapshot::delta(diff, change_count),
+            None => Snapshot::keyframe(canvas.clone(), change_count),
+        });
+    }
+
+    /// Bytes used by the pixel data of every snapshot
+    pub fn snapshot_bytes(&self) -> usize {
+        self.snapshots.iter().map(Snapshot::stored_bytes).sum()
+    }
+
+    /// Regenerate every snapshot, and the palette they share, by replaying all
+    /// changes onto a blank canvas the size of the first snapshot.
+    /// Only meaningful for histories that were never pruned.
+    pub fn rebuild_snapshots(&mut self) -> Result<(), CanvasError> {
+        let initial = self.snapshot_canvas(0);
+        let mut canvas = Canvas::new(initial.width(), initial.height())?;
+        self.snapshots = vec![Snapshot::keyframe(canvas.clone(), 0)];
+
+        for count in 1..=self.changes.len() {
+            self.replay(&mut canvas, count - 1..count);
+            if count.is_multiple_of(self.snapshot_interval) {
+                self.push_snapshot(&canvas, count);
+            }
+        }
+        Ok(())
+    }
+
     /// Record a new change and create a snapshot if needed
     pub fn record_change(&mut self, change: Change, current_canvas: &Canvas) {
+        if let ChangeEvent::SetPalette { colors } = &change.event {
+            self.allowed_colors = colors.clone();
+        }
         self.changes.push(change);
-        
-        if self.changes.len() % self.snapshot_interval == 0 {
-            let snapshot = Snapshot {
-                canvas: current_canvas.clone(),
-                change_count: self.changes.len(),
-            };
-            self.snapshots.push(snapshot);
+
+        let change_count = self.current_change_count();
+        if change_count.is_multiple_of(self.snapshot_interval) {
+            self.push_snapshot(current_canvas, change_count);
         }
     }
 
-    /// Get the current number of changes
+    /// The only colors paints may use, or `None` if any color is allowed
+    pub fn allowed_colors(&self) -> Option<&[Color]> {
+        self.allowed_colors.as_deref()
+    }
+
+    /// Restrict paints to `colors` without recording a change, e.g. for a new canvas
+    pub fn set_allowed_colors(&mut self, colors: Option<Vec<Color>>) {
+        self.allowed_colors = colors;
+    }
+
+    /// The paint cooldown configured for `role` in milliseconds, if any
+    pub fn cooldown(&self, role: &str) -> Option<u64> {
+        self.cooldowns.get(role).copied()
+    }
+
+    /// Configure the paint cooldown for `role`, or fall back to the server default with `None`
+    pub fn set_cooldown(&mut self, role: &str, cooldown_ms: Option<u64>) {
+        match cooldown_ms {
+            Some(cooldown_ms) => self.cooldowns.insert(role.to_string(), cooldown_ms),
+            None => self.cooldowns.remove(role),
+        };
+    }
+
+    /// Whether `identity` is banned from this canvas at unix time `now_ms`
+    pub fn is_banned(&self, identity: &str, now_ms: u64) -> bool {
+        self.bans
+            .get(identity)
+            .is_some_and(|until| until.is_none_or(|until| until > now_ms))
+    }
+
+    /// Whether a ban on `identity` is recorded, even if it has expired
+    pub fn has_ban(&self, identity: &str) -> bool {
+        self.bans.contains_key(identity)
+    }
+
+    /// Ban `identity` until unix time `until_ms`, or for good with `None`.
+    /// Expired bans are dropped at the same time.
+    pub fn ban(&mut self, identity: &str, until_ms: Option<u64>, now_ms: u64) {
+        self.bans.retain(|_, until| until.is_none_or(|until| until > now_ms));
+        self.bans.insert(identity.to_string(), until_ms);
+    }
+
+    /// Lift the ban on `identity`, returning whether there was one
+    pub fn unban(&mut self, identity: &str) -> bool {
+        self.bans.remove(identity).is_some()
+    }
+
+    /// Get the current number of changes, including pruned ones
     pub fn current_change_count(&self) -> usize {
-        self.changes.len()
+        self.first_index + self.changes.len()
+    }
+
+    /// Index of the oldest change still held in full
+    pub fn first_retained_index(&self) -> usize {
+        self.first_index
+    }
+
+    /// The changes still held in full, starting at `first_retained_index`
+    pub fn retained_changes(&self) -> &[Change] {
+        &self.changes
+    }
+
+    /// Get the change at `index`, if it hasn't been pruned
+    pub fn change(&self, index: usize) -> Option<&Change> {
+        self.changes.get(index.checked_sub(self.first_index)?)
     }
 
     /// Get the latest snapshot before or at the given change index
     pub fn latest_snapshot_before(&self, change_index: usize) -> Option<&Snapshot> {
+        self.latest_snapshot_index(change_index).map(|index| &self.snapshots[index])
+    }
+
+    fn latest_snapshot_index(&self, change_index: usize) -> Option<usize> {
         self.snapshots
+            .partition_point(|s| s.change_count <= change_index)
+            .checked_sub(1)
+    }
+
+    fn has_snapshot_at(&self, change_count: usize) -> bool {
+        self.snapshots.binary_search_by_key(&change_count, |s| s.change_count).is_ok()
+    }
+
+    /// Number of changes made at or before `timestamp` (Unix milliseconds).
+    /// Returns `None` if the timestamp falls before the retained history.
+    pub fn change_count_at_time(&self, timestamp: u64) -> Option<usize> {
+        // `World` never records a change with an earlier timestamp than the one before it
+        let retained = self.changes.partition_point(|change| change.timestamp <= timestamp);
+        if retained == 0 && self.first_index > 0 {
+            return None;
+        }
+        Some(self.first_index + retained)
+    }
+
+    /// Reconstruct the canvas as it was at `timestamp` (Unix milliseconds),
+    /// if that point is still within the retained history
+    pub fn canvas_at_time(&self, timestamp: u64) -> Option<Canvas> {
+        self.change_count_at_time(timestamp).map(|count| self.canvas_at(count))
+    }
+
+    /// Get the changes made after the first `since` changes, if they can be replayed
+    /// onto a canvas that has seen exactly those `since` changes.
+    /// Returns `None` if `since` is ahead of history or pruned, or the changes
+    /// include a resize or revert, which can't be expressed as pixel updates.
+    pub fn changes_since(&self, since: usize) -> Option<&[Change]> {
+        if since < self.first_index || since > self.current_change_count() {
+            return None;
+        }
+
+        let changes = &self.changes[since - self.first_index..];
+        let replayable = changes.iter().all(|change| {
+            matches!(change.event, ChangeEvent::Paint { .. } | ChangeEvent::Restore { .. })
+        });
+        replayable.then_some(changes)
+    }
+
+    /// Reconstruct the canvas as it was after the first `change_count` changes,
+    /// honouring revert markers. Before the retained history only snapshots
+    /// remain, so the nearest earlier snapshot is returned there.
+    pub fn canvas_at(&self, change_count: usize) -> Canvas {
+        let change_count = change_count.min(self.current_change_count());
+        let index = self
+            .latest_snapshot_index(change_count)
+            .expect("History must have at least one snapshot");
+        let mut canvas = self.snapshot_canvas(index);
+        if change_count >= self.first_index {
+            self.replay(&mut canvas, self.snapshots[index].change_count..change_count);
+        }
+        canvas
+    }
+
+    /// Copy out what `canvas_at(change_count)` needs, so the canvas can be
+    /// rebuilt without holding on to the history. Revert markers are resolved
+    /// here, leaving only changes that apply directly to the snapshot canvas.
+    pub fn reconstruction_at(&self, change_count: usize) -> Reconstruction {
+        let change_count = change_count.min(self.current_change_count());
+        let index = self
+            .latest_snapshot_index(change_count)
+            .expect("History must have at least one snapshot");
+        let keyframe = self.snapshots[..=index]
             .iter()
-            .filter(|s| s.change_count <= change_index)
-            .max_by_key(|s| s.change_count)
+            .rposition(Snapshot::is_keyframe)
+            .expect("The first snapshot is always a keyframe");
+
+        let changes = if change_count >= self.first_index {
+            &self.changes[self.snapshots[index].change_count - self.first_index..change_count - self.first_index]
+        } else {
+            &[]
+        };
+        // Only the last revert matters, as it replaces the canvas outright
+        if let Some(position) = changes.iter().rposition(|change| matches!(change.event, ChangeEvent::Revert { .. })) {
+            let ChangeEvent::Revert { target_index } = changes[position].event else {
+                unreachable!();
+            };
+            let mut reconstruction = self.reconstruction_at(target_index + 1);
+            reconstruction.changes.extend_from_slice(&changes[position + 1..]);
+            return reconstruction;
+        }
+
+        Reconstruction {
+            snapshots: self.snapshots[keyframe..=index].to_vec(),
+            changes: changes.to_vec(),
+        }
     }
 
-    /// Reconstruct a canvas from history by replaying all changes
-    pub fn reconstruct_canvas(&self) -> Canvas {
-        use super::change::ChangeEvent;
-        
-        // Always start from the last snapshot (there's always at least one)
-        let snapshot = self.snapshots.last().expect("History must have at least one snapshot");
-        let mut canvas = snapshot.canvas.clone();
-        
-        // Replay changes since the snapshot
-        for change in &self.changes[snapshot.change_count..] {
+    /// Whether `canvas_at(change_count)` is exact rather than the nearest snapshot
+    pub fn is_reconstructible(&self, change_count: usize) -> bool {
+        change_count >= self.first_index
+            || self.has_snapshot_at(change_count)
+    }
+
+    /// Apply the changes in `range` to `canvas`, which must reflect exactly the
+    /// changes before `range.start`. The range must lie within the retained history.
+    pub fn replay(&self, canvas: &mut Canvas, range: Range<usize>) {
+        for change in &self.changes[range.start - self.first_index..range.end - self.first_index] {
+            match &change.event {
+                ChangeEvent::Revert { target_index } => {
+                    // Markers always point backwards, so this recursion terminates
+                    *canvas = self.canvas_at(target_index + 1);
+                }
+                event => apply_event(canvas, event),
+            }
+        }
+    }
+
+    /// Compute the pixels needed to undo every paint matching `filter`.
+    /// Each pixel whose latest write matches is restored to the most recent
+    /// value written by a non-matching change. Only retained changes since the
+    /// last resize or revert are considered, as earlier coordinates and values
+    /// no longer map directly onto the current canvas.
+    pub fn restore_matching(&self, filter: &PaintFilter) -> Vec<PixelPaint> {
+        let boundary = self
+            .changes
+            .iter()
+            .rposition(|change| matches!(change.event, ChangeEvent::Resize { .. } | ChangeEvent::Revert { .. }))
+            .map_or(0, |index| index + 1);
+
+        // Per pixel: last value written by a non-matching change, and whether the latest write matched
+        let mut pixels: HashMap<(usize, usize), (Option<&Color>, bool)> = HashMap::new();
+        for change in &self.changes[boundary..] {
             match &change.event {
                 ChangeEvent::Paint { x, y, color } => {
-                    let _ = canvas.set_pixel(*x, *y, color.clone());
+                    let entry = pixels.entry((*x, *y)).or_insert((None, false));
+                    if filter.matches(change) {
+                        entry.1 = true;
+                    } else {
+                        *entry = (Some(color), false);
+                    }
                 }
-                ChangeEvent::Resize { anchor, width, height } => {
-                    let _ = canvas.resize(*width, *height, *anchor);
+                ChangeEvent::Restore { pixels: restored } => {
+                    for pixel in restored {
+                        pixels.insert((pixel.x, pixel.y), (Some(&pixel.color), false));
+                    }
                 }
+                ChangeEvent::Import { x, y, width, pixels: imported, .. } => {
+                    for (offset, color) in imported.iter().enumerate() {
+                        if let Some(color) = color {
+                            pixels.insert((x + offset % width, y + offset / width), (Some(color), false));
+                        }
+                    }
+                }
+                ChangeEvent::Resize { .. } | ChangeEvent::Revert { .. } | ChangeEvent::SetPalette { .. } => {}
             }
         }
-        
-        canvas
+
+        // Pixels never written by a non-matching change fall back to the boundary state
+        let base = self.canvas_at(self.first_index + boundary);
+        let mut restored: Vec<PixelPaint> = pixels
+            .into_iter()
+            .filter(|(_, (_, matched))| *matched)
+            .filter_map(|((x, y), (kept, _))| {
+                let color = match kept {
+                    Some(color) => *color,
+                    None => base.get_pixel(x, y).ok()?,
+                };
+                Some(PixelPaint { x, y, color })
+            })
+            .collect();
+        restored.sort_unstable_by_key(|pixel| (pixel.y, pixel.x));
+        restored
     }
 
-    /// Rollback to a specific change index (destructive)
-    /// Index is 0-based. Truncates all changes after target_index.
-    pub fn rollback_to_index(&mut self, target_index: usize) -> Result<(), RollbackError> {
-        if target_index >= self.changes.len() {
+    /// Reconstruct the current canvas by replaying all changes
+    pub fn reconstruct_canvas(&self) -> Canvas {
+        self.canvas_at(self.current_change_count())
+    }
+
+    /// Rollback to a specific change index (non-destructive).
+    /// Index is 0-based. Appends a revert marker restoring the canvas as it was
+    /// right after `target_index`, and returns the restored canvas.
+    pub fn rollback_to_index(&mut self, target_index: usize, timestamp: u64, author: Option<String>) -> Result<Canvas, RollbackError> {
+        let change_count = self.current_change_count();
+        if target_index >= change_count {
             return Err(RollbackError::IndexOutOfBounds {
                 target: target_index,
-                max: self.changes.len().saturating_sub(1),
+                max: change_count.saturating_sub(1),
             });
         }
-        
-        // Truncate changes to keep only up to and including target
-        self.changes.truncate(target_index + 1);
-        let new_change_count = self.changes.len();
-        
-        // Remove snapshots that are after the new change count
-        self.snapshots.retain(|snapshot| snapshot.change_count <= new_change_count);
-        
-        // Ensure we always have at least the initial snapshot
-        if self.snapshots.is_empty() {
-            panic!("History must always have at least one snapshot");
-        }
-        
-        Ok(())
+        if !self.is_reconstructible(target_index + 1) {
+            return Err(RollbackError::Pruned {
+                target: target_index,
+                earliest: self.first_index.saturating_sub(1),
+            });
+        }
+
+        let canvas = self.record_revert(target_index, timestamp, author);
+        self.undo_stack.push(self.current_change_count() - 1);
+        self.redo_stack.clear();
+        Ok(canvas)
+    }
+
+    /// Undo the most recent rollback by restoring the canvas as it was just before it
+    pub fn undo_rollback(&mut self, timestamp: u64, author: Option<String>) -> Result<Canvas, RollbackError> {
+        let marker = self.undo_stack.pop().ok_or(RollbackError::NothingToUndo)?;
+
+        // A marker is never the first change, since rollback needs a target before it
+        let canvas = self.record_revert(marker - 1, timestamp, author);
+        self.redo_stack.push(marker);
+        Ok(canvas)
+    }
+
+    /// Redo the most recently undone rollback
+    pub fn redo_rollback(&mut self, timestamp: u64, author: Option<String>) -> Result<Canvas, RollbackError> {
+        let marker = self.redo_stack.pop().ok_or(RollbackError::NothingToRedo)?;
+
+        let canvas = self.record_revert(marker, timestamp, author);
+        self.undo_stack.push(self.current_change_count() - 1);
+        Ok(canvas)
+    }
+
+    fn record_revert(&mut self, target_index: usize, timestamp: u64, author: Option<String>) -> Canvas {
+        let canvas = self.canvas_at(target_index + 1);
+        let change = Change {
+            event: ChangeEvent::Revert { target_index },
+            timestamp,
+            author,
+        };
+        self.record_change(change, &canvas);
+        canvas
+    }
+
+    /// Drop the changes that fall outside `policy`, as of `now` in Unix milliseconds.
+    /// Pruning stops at a snapshot so the retained history can still be replayed;
+    /// older snapshots are thinned to a logarithmic schedule, keeping any that a
+    /// retained revert marker needs. Returns the pruned segment as a standalone
+    /// history, or `None` if nothing was pruned.
+    pub fn prune(&mut self, policy: &RetentionPolicy, now: u64) -> Option<History> {
+        let change_count = self.current_change_count();
+        let by_count = policy.max_changes.map(|max| change_count.saturating_sub(max));
+        let by_age = policy.max_age_ms.map(|max_age| {
+            let cutoff = now.saturating_sub(max_age);
+            self.changes
+                .iter()
+                .position(|change| change.timestamp >= cutoff)
+                .map_or(change_count, |index| self.first_index + index)
+        });
+        let keep_from = match (by_count, by_age) {
+            (Some(a), Some(b)) => a.min(b),
+            (Some(a), None) | (None, Some(a)) => a,
+            (None, None) => return None,
+        };
+
+        let new_first = self.latest_snapshot_before(keep_from)?.change_count;
+        if new_first <= self.first_index {
+            return None;
+        }
+
+        // States that retained revert markers restore must stay reconstructible
+        let pinned: BTreeSet<usize> = self.changes[new_first - self.first_index..]
+            .iter()
+            .filter_map(|change| match change.event {
+                ChangeEvent::Revert { target_index } if target_index + 1 < new_first => Some(target_index + 1),
+                _ => None,
+            })
+            .collect();
+        let pinned_snapshots: Vec<Snapshot> = pinned
+            .iter()
+            .filter(|&&count| !self.has_snapshot_at(count))
+            .map(|&count| Snapshot::keyframe(self.canvas_at(count), count))
+            .collect();
+
+        // The pruned segment keeps every snapshot up to where it ends, so it replays on its own
+        let end = self.snapshots.partition_point(|snapshot| snapshot.change_count <= new_first);
+        let segment = History {
+            snapshots: self.snapshots[..end].to_vec(),
+            changes: self.changes.drain(..new_first - self.first_index).collect(),
+            snapshot_interval: self.snapshot_interval,
+            keyframe_interval: self.keyframe_interval,
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
+            first_index: self.first_index,
+            // Archived segments are read-only, so no paint is ever checked against it
+            allowed_colors: None,
+            cooldowns: BTreeMap::new(),
+            bans: BTreeMap::new(),
+        };
+        self.first_index = new_first;
+        self.undo_stack.retain(|&marker| marker >= new_first);
+        self.redo_stack.retain(|&marker| marker >= new_first);
+
+        // Keep the newest snapshot in each power-of-two bucket of distance from the retained history
+        let interval = self.snapshot_interval;
+        let mut buckets = BTreeSet::new();
+        let keep: Vec<bool> = self
+            .snapshots
+            .iter()
+            .rev()
+            .map(|snapshot| {
+                if snapshot.change_count >= new_first || pinned.contains(&snapshot.change_count) {
+                    return true;
+                }
+                let distance = (new_first - snapshot.change_count).div_ceil(interval);
+                buckets.insert(distance.ilog2())
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .collect();
+
+        // Thinning breaks delta chains, so everything kept up to the new start becomes a keyframe
+        for (index, _) in keep[..end].iter().enumerate().filter(|(_, kept)| **kept) {
+            if !self.snapshots[index].is_keyframe() {
+                self.snapshots[index] = Snapshot::keyframe(self.snapshot_canvas(index), self.snapshots[index].change_count);
+            }
+        }
+        let mut keep = keep.into_iter();
+        let mut snapshots: Vec<Snapshot> = std::mem::take(&mut self.snapshots)
+            .into_iter()
+            .filter(|_| keep.next().unwrap_or(true))
+            .collect();
+        snapshots.extend(pinned_snapshots);
+        snapshots.sort_unstable_by_key(|snapshot| snapshot.change_count);
+        self.snapshots = snapshots;
+
+        Some(segment)
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,108 @@
+use super::canvas::Canvas;
+use super::color::Color;
+
+/// Render the canvas as tightly packed RGB bytes in row-major order
+pub fn canvas_rgb(canvas: &Canvas) -> Vec<u8> {
+    // Resolve each palette entry once rather than once per pixel
+    let palette_lock = canvas.palette();
+    let colors: Vec<[u8; 3]> = {
+        let palette = palette_lock.read().unwrap();
+        palette
+            .colors()
+            .iter()
+            .map(|color| color.rgb())
+            .collect()
+    };
+
+    let mut rgb = Vec::with_capacity(canvas.pixels().len() * 3);
+    for &index in canvas.pixels() {
+        rgb.extend_from_slice(colors.get(index as usize).unwrap_or(&[0xFF, 0xFF, 0xFF]));
+    }
+    rgb
+}
+
+/// Encode the canvas as an RGB PNG image
+pub fn encode_png(canvas: &Canvas) -> Result<Vec<u8>, png::EncodingError> {
+    let mut buffer = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut buffer, canvas.width() as u32, canvas.height() as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&canvas_rgb(canvas))?;
+    }
+    Ok(buffer)
+}
+
+/// Largest image, in pixels, that can be imported in one change
+const MAX_IMPORT_PIXELS: usize = 1024 * 1024;
+
+/// A decoded image ready to be stamped onto a canvas.
+/// `pixels` is row-major; `None` marks fully transparent pixels that are left
+/// untouched, and translucent pixels are composited over the canvas.
+pub struct ImportImage {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Option<Color>>,
+}
+
+/// Decode a PNG of any color type, quantizing its colors
+pub fn decode_png(data: &[u8]) -> Result<ImportImage, String> {
+    let mut decoder = png::Decoder::new(std::io::Cursor::new(data));
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info().map_err(|e| format!("Invalid PNG: {}", e))?;
+
+    let (width, height) = {
+        let info = reader.info();
+        (info.width as usize, info.height as usize)
+    };
+    check_import_size(width, height)?;
+
+    let mut buffer = vec![0; reader.output_buffer_size().ok_or("PNG is too large")?];
+    let frame = reader.next_frame(&mut buffer).map_err(|e| format!("Invalid PNG: {}", e))?;
+    let bytes = &buffer[..frame.buffer_size()];
+
+    let pixels = match frame.color_type {
+        png::ColorType::Rgb => bytes.chunks_exact(3).map(|p| quantize([p[0], p[1], p[2], 0xFF])).collect(),
+        png::ColorType::Rgba => bytes.chunks_exact(4).map(|p| quantize([p[0], p[1], p[2], p[3]])).collect(),
+        png::ColorType::Grayscale => bytes.iter().map(|&v| quantize([v, v, v, 0xFF])).collect(),
+        png::ColorType::GrayscaleAlpha => bytes.chunks_exact(2).map(|p| quantize([p[0], p[0], p[0], p[1]])).collect(),
+        png::ColorType::Indexed => return Err("Indexed PNG was not expanded".to_string()),
+    };
+
+    Ok(ImportImage { width, height, pixels })
+}
+
+/// Decode a raw, tightly packed RGB buffer of the given dimensions, quantizing its colors
+pub fn decode_rgb(data: &[u8], width: usize, height: usize) -> Result<ImportImage, String> {
+    check_import_size(width, height)?;
+    if data.len() != width * height * 3 {
+        return Err(format!(
+            "Expected {} bytes of RGB data for {}x{}, got {}",
+            width * height * 3,
+            width,
+            height,
+            data.len()
+        ));
+    }
+
+    let pixels = data.chunks_exact(3).map(|p| quantize([p[0], p[1], p[2], 0xFF])).collect();
+    Ok(ImportImage { width, height, pixels })
+}
+
+fn check_import_size(width: usize, height: usize) -> Result<(), String> {
+    if width == 0 || height == 0 || width.saturating_mul(height) > MAX_IMPORT_PIXELS {
+        return Err(format!(
+            "Image must be non-empty and at most {} pixels, got {}x{}",
+            MAX_IMPORT_PIXELS, width, height
+        ));
+    }
+    Ok(())
+}
+
+/// Snap a color to 4 bits per channel so an opaque photo adds at most 4096
+/// palette entries. Pixels that snap to fully transparent are skipped.
+fn quantize(rgba: [u8; 4]) -> Option<Color> {
+    let color = Color::from_rgba(rgba.map(|v| ((v as u16 + 8) / 17) as u8 * 17));
+    (color != Color::TRANSPARENT).then_some(color)
+}
@@ -2,17 +2,50 @@ pub mod canvas;
 pub mod change;
 pub mod color;
 pub mod history;
+pub mod image;
 pub mod persistence;
 pub mod palette;
+pub mod wal;
 
 use canvas::{Canvas, CanvasError};
-use change::{Change, ChangeEvent};
-use history::History;
+use change::{Change, ChangeEvent, PaintFilter, PixelPaint};
+use color::Color;
+use history::{History, RetentionPolicy, RollbackError, DEFAULT_KEYFRAME_INTERVAL};
+use wal::{LogEntry, LogRecord, LogSyncer, WriteAheadLog};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
 use std::time::{SystemTime, UNIX_EPOCH};
 
+/// Why an operation on a `World` was not applied
+#[derive(Debug)]
+pub enum WorldError {
+    Canvas(CanvasError),
+    Rollback(RollbackError),
+    /// The operation could not be written to the write-ahead log
+    Log,
+}
+
+impl From<CanvasError> for WorldError {
+    fn from(error: CanvasError) -> Self {
+        WorldError::Canvas(error)
+    }
+}
+
+impl From<RollbackError> for WorldError {
+    fn from(error: RollbackError) -> Self {
+        WorldError::Rollback(error)
+    }
+}
+
 pub struct World {
     pub canvas: Canvas,
     pub history: History,
+    /// Log that every applied operation is appended to, if persistence is enabled
+    log: Option<WriteAheadLog>,
+    /// Timestamp of the latest paint per cooldown key, for enforcing cooldowns:
+    /// the session token users among the authors of paints, plus keys passed to
+    /// `start_cooldown`. Only the former are rebuilt from the history on restart.
+    last_paints: HashMap<String, u64>,
 }
 
 #[allow(dead_code)]
@@ -20,44 +53,308 @@ impl World {
     /// Create a new world with the given canvas dimensions and history snapshot interval
     pub fn new(width: usize, height: usize, snapshot_interval: usize) -> Result<Self, CanvasError> {
         let canvas = Canvas::new(width, height)?;
-        let history = History::new(snapshot_interval, &canvas);
+        let history = History::new(snapshot_interval, DEFAULT_KEYFRAME_INTERVAL, &canvas);
 
-        Ok(World { canvas, history })
+        Ok(World { canvas, history, log: None, last_paints: HashMap::new() })
     }
 
     /// Create a world from an existing history
     pub fn from(history: History) -> Self {
         let canvas = history.reconstruct_canvas();
-        World { canvas, history }
+        let mut world = World { canvas, history, log: None, last_paints: HashMap::new() };
+        for change in world.history.retained_changes() {
+            if let (ChangeEvent::Paint { .. }, Some(author)) = (&change.event, &change.author)
+                && keeps_cooldown(author)
+            {
+                world.last_paints.insert(author.clone(), change.timestamp);
+            }
+        }
+        world
+    }
+
+    /// Replay records read from a write-ahead log, then append future operations to `log`.
+    /// Records already contained in the history are skipped. Returns the number replayed.
+    pub fn attach_log(&mut self, log: WriteAheadLog, records: Vec<LogRecord>) -> usize {
+        let replayed = self.replay_log(records);
+        self.log = Some(log);
+        replayed
     }
 
-    /// Apply a change event to the world
-    pub fn apply_event(&mut self, event: ChangeEvent) -> Result<(), CanvasError> {
+    /// Apply logged operations that follow on from the current history.
+    /// Returns the number of records applied.
+    pub fn replay_log(&mut self, records: Vec<LogRecord>) -> usize {
+        let mut replayed = 0;
+        for (change_index, entry) in records {
+            let count = self.change_count();
+            if change_index < count {
+                continue;
+            }
+            if change_index > count {
+                eprintln!("Write-ahead log skips from change {} to {}, ignoring the rest", count, change_index);
+                break;
+            }
 
-        match &event {
-            ChangeEvent::Paint { x, y, color } => {
-                self.canvas.set_pixel(*x, *y, color.clone())?;
+            // Settings can be applied again harmlessly if the history file already holds them
+            let result = match entry {
+                LogEntry::SetCooldown { role, cooldown_ms } => {
+                    self.history.set_cooldown(&role, cooldown_ms);
+                    Ok(())
+                }
+                LogEntry::Ban { identity, until_ms, timestamp } => {
+                    self.history.ban(&identity, until_ms, timestamp);
+                    Ok(())
+                }
+                LogEntry::Unban { identity } => {
+                    self.history.unban(&identity);
+                    Ok(())
+                }
+                LogEntry::Change(change) => self.apply_change(change).map_err(|e| format!("{:?}", e)),
+                LogEntry::Rollback { target_index, timestamp, author } => self
+                    .history
+                    .rollback_to_index(target_index, timestamp, author)
+                    .map(|canvas| self.canvas = canvas)
+                    .map_err(|e| format!("{:?}", e)),
+                LogEntry::UndoRollback { timestamp, author } => self
+                    .history
+                    .undo_rollback(timestamp, author)
+                    .map(|canvas| self.canvas = canvas)
+                    .map_err(|e| format!("{:?}", e)),
+                LogEntry::RedoRollback { timestamp, author } => self
+                    .history
+                    .redo_rollback(timestamp, author)
+                    .map(|canvas| self.canvas = canvas)
+                    .map_err(|e| format!("{:?}", e)),
+            };
+            if let Err(e) = result {
+                eprintln!("Failed to replay logged change {}: {}", change_index, e);
+                break;
             }
-            ChangeEvent::Resize { anchor, width, height } => {
-                self.canvas.resize(*width, *height, *anchor)?;
+            replayed += 1;
+        }
+        replayed
+    }
+
+    /// A handle for making logged operations durable, if persistence is enabled
+    pub fn log_syncer(&self) -> Option<Arc<LogSyncer>> {
+        self.log.as_ref().map(WriteAheadLog::syncer)
+    }
+
+    /// Drop changes outside the retention policy, returning the pruned segment
+    pub fn prune_history(&mut self, policy: &RetentionPolicy) -> Option<History> {
+        let now = self.get_current_timestamp();
+        self.history.prune(policy, now)
+    }
+
+    /// Drop palette colors that neither the canvas nor any snapshot uses, once
+    /// at least `min_unused` of them have piled up. The live colors move to a new
+    /// palette, so canvases cloned before this keep rendering correctly.
+    /// Returns the number of colors dropped, or `None` if nothing was done.
+    pub fn collect_palette(&mut self, min_unused: usize) -> Option<usize> {
+        let palette = self.canvas.palette();
+        let (retained, mapping) = {
+            let palette = palette.read().unwrap();
+            let mut used = vec![false; palette.len()];
+            // Blank and resized canvases fill with index 0, so white must stay there
+            used[0] = true;
+            for &index in self.canvas.pixels() {
+                used[index as usize] = true;
+            }
+            self.history.mark_used_colors(&mut used);
+
+            let unused = used.iter().filter(|&&used| !used).count();
+            if unused == 0 || unused < min_unused {
+                return None;
             }
+            palette.retain_used(&used)
+        };
+
+        let dropped = mapping.len() - retained.len();
+        let retained = Arc::new(RwLock::new(retained));
+        self.canvas.remap_palette(&mapping, retained.clone());
+        self.history.remap_palette(&mapping, retained);
+        Some(dropped)
+    }
+
+    /// Write the full history to `history_file` and empty the log, if anything
+    /// was logged since the last compaction or `force` is set
+    pub fn compact(&mut self, history_file: &str, force: bool) -> Result<(), Box<dyn std::error::Error>> {
+        if !force && self.log.as_ref().is_some_and(|log| log.is_empty()) {
+            return Ok(());
+        }
+        persistence::save_history(&self.history, history_file)?;
+        if let Some(log) = &mut self.log {
+            log.truncate()?;
+        }
+        Ok(())
+    }
+
+    /// Write `entry` to the log, then carry it out with `apply`. Nothing is
+    /// applied if the log can't be written, and the entry is taken back out
+    /// of the log if `apply` fails.
+    fn logged<T, E>(&mut self, entry: LogEntry, apply: impl FnOnce(&mut Self) -> Result<T, E>) -> Result<T, WorldError>
+    where
+        WorldError: From<E>,
+    {
+        let change_index = self.change_count();
+        if let Some(log) = &mut self.log {
+            log.append(change_index, &entry).map_err(|e| {
+                eprintln!("Failed to append change {} to write-ahead log: {}", change_index, e);
+                WorldError::Log
+            })?;
+        }
+
+        let result = apply(self);
+        if result.is_err()
+            && let Some(log) = &mut self.log
+            && let Err(e) = log.retract()
+        {
+            eprintln!("Failed to remove unapplied change {} from write-ahead log: {}", change_index, e);
         }
+        Ok(result?)
+    }
 
+    /// Apply a change event to the world, attributing it to `author`
+    pub fn apply_event(&mut self, event: ChangeEvent, author: Option<String>) -> Result<(), WorldError> {
         let change = Change {
-            event,
+            event: self.composite(event),
             timestamp: self.get_current_timestamp(),
+            author,
         };
-        self.history.record_change(change, &self.canvas);
+        self.logged(LogEntry::Change(change.clone()), |world| world.apply_change(change))
+    }
 
+    /// Resolve translucent colors against the pixels they are drawn over, so
+    /// recorded changes and the canvas only ever hold opaque colors.
+    /// A fully transparent paint erases the pixel back to white. Imported pixels
+    /// are then snapped to the nearest color of a restricted palette.
+    fn composite(&self, event: ChangeEvent) -> ChangeEvent {
+        let below = |x, y| self.canvas.get_pixel(x, y).unwrap_or(Color::WHITE);
+        match event {
+            ChangeEvent::Paint { x, y, color } if !color.is_opaque() => {
+                let color = match color {
+                    Color::TRANSPARENT => Color::WHITE,
+                    color => color.over(below(x, y)),
+                };
+                ChangeEvent::Paint { x, y, color }
+            }
+            ChangeEvent::Import { x, y, width, height, mut pixels } => {
+                let allowed = self.history.allowed_colors();
+                // Imports repeat a few thousand quantized colors at most
+                let mut nearest = HashMap::new();
+                for (offset, pixel) in pixels.iter_mut().enumerate() {
+                    let Some(color) = pixel else {
+                        continue;
+                    };
+                    if !color.is_opaque() {
+                        *color = color.over(below(x + offset % width, y + offset / width));
+                    }
+                    if let Some(allowed) = allowed {
+                        *color = *nearest.entry(*color).or_insert_with(|| color.nearest(allowed));
+                    }
+                }
+                ChangeEvent::Import { x, y, width, height, pixels }
+            }
+            event => event,
+        }
+    }
+
+    /// Apply an already timestamped change to the canvas and record it
+    fn apply_change(&mut self, change: Change) -> Result<(), CanvasError> {
+        match &change.event {
+            ChangeEvent::Paint { x, y, color } => {
+                self.canvas.set_pixel(*x, *y, *color)?;
+            }
+            ChangeEvent::Resize { anchor, width, height } => {
+                self.canvas.resize(*width, *height, *anchor)?;
+            }
+            ChangeEvent::Revert { target_index } => {
+                self.canvas = self.history.canvas_at(*target_index + 1);
+            }
+            ChangeEvent::Restore { pixels } => {
+                for pixel in pixels {
+                    self.canvas.set_pixel(pixel.x, pixel.y, pixel.color)?;
+                }
+            }
+            ChangeEvent::Import { x, y, width, pixels, .. } => {
+                self.canvas.stamp(*x, *y, *width, pixels)?;
+            }
+            ChangeEvent::SetPalette { .. } => {}
+        }
+
+        if let (ChangeEvent::Paint { .. }, Some(author)) = (&change.event, &change.author)
+            && keeps_cooldown(author)
+        {
+            self.last_paints.insert(author.clone(), change.timestamp);
+        }
+        self.history.record_change(change, &self.canvas);
         Ok(())
     }
 
-    /// Get the current Unix timestamp in milliseconds
+    /// Get the current Unix timestamp in milliseconds, never earlier than the
+    /// latest change so history stays ordered by time even if the clock steps back
     fn get_current_timestamp(&self) -> u64 {
-        SystemTime::now()
+        let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("System time is before Unix epoch")
-            .as_millis() as u64
+            .as_millis() as u64;
+        let latest = self.history.retained_changes().last().map_or(0, |change| change.timestamp);
+        now.max(latest)
+    }
+
+    /// Milliseconds until `key` may paint again under a cooldown of `cooldown_ms`,
+    /// counted from its latest paint
+    pub fn cooldown_remaining(&self, key: &str, cooldown_ms: u64) -> u64 {
+        match self.last_paints.get(key) {
+            Some(&last) => cooldown_ms.saturating_sub(self.get_current_timestamp().saturating_sub(last)),
+            None => 0,
+        }
+    }
+
+    /// Start the cooldown of `key` from now, for paints whose author isn't the
+    /// key they are rate limited by
+    pub fn start_cooldown(&mut self, key: &str) {
+        let now = self.get_current_timestamp();
+        self.last_paints.insert(key.to_string(), now);
+    }
+
+    /// Forget paints too old for any cooldown up to `longest_ms` to still apply
+    pub fn prune_cooldowns(&mut self, longest_ms: u64) {
+        let now = self.get_current_timestamp();
+        self.last_paints.retain(|_, last| now.saturating_sub(*last) < longest_ms);
+    }
+
+    /// Whether `identity` is currently banned from changing this canvas
+    pub fn is_banned(&self, identity: &str) -> bool {
+        self.history.is_banned(identity, self.get_current_timestamp())
+    }
+
+    /// Ban `identity` from changing this canvas for `duration_ms`, or for good with `None`
+    pub fn ban(&mut self, identity: &str, duration_ms: Option<u64>) -> Result<(), WorldError> {
+        let now = self.get_current_timestamp();
+        let until_ms = duration_ms.map(|duration| now.saturating_add(duration));
+        let entry = LogEntry::Ban { identity: identity.to_string(), until_ms, timestamp: now };
+        self.logged(entry, |world| {
+            world.history.ban(identity, until_ms, now);
+            Ok::<_, WorldError>(())
+        })
+    }
+
+    /// Lift the ban on `identity`, returning whether there was one
+    pub fn unban(&mut self, identity: &str) -> Result<bool, WorldError> {
+        if !self.history.has_ban(identity) {
+            return Ok(false);
+        }
+        let entry = LogEntry::Unban { identity: identity.to_string() };
+        self.logged(entry, |world| Ok::<_, WorldError>(world.history.unban(identity)))
+    }
+
+    /// Configure the paint cooldown for `role`, or fall back to the server default with `None`
+    pub fn set_cooldown(&mut self, role: &str, cooldown_ms: Option<u64>) -> Result<(), WorldError> {
+        let entry = LogEntry::SetCooldown { role: role.to_string(), cooldown_ms };
+        self.logged(entry, |world| {
+            world.history.set_cooldown(role, cooldown_ms);
+            Ok::<_, WorldError>(())
+        })
     }
 
     /// Get the canvas dimensions
@@ -70,12 +367,71 @@ impl World {
         self.history.current_change_count()
     }
 
-    /// Rollback the world to a specific change index (destructive)
+    /// Find the change that last painted the pixel at (x, y).
+    /// Returns `None` if it was never painted since the last resize, or the
+    /// painting change has been pruned.
+    pub fn last_paint_at(&self, x: usize, y: usize) -> Option<&Change> {
+        let mut index = self.history.current_change_count();
+        while index > 0 {
+            index -= 1;
+            let change = self.history.change(index)?;
+            match &change.event {
+                // Coordinates before a resize refer to a different layout
+                ChangeEvent::Resize { .. } => return None,
+                // The pixel came from the reverted-to state, so continue searching from there
+                ChangeEvent::Revert { target_index } => index = *target_index + 1,
+                event if event.color_at(x, y).is_some() => return Some(change),
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// Rollback the world to a specific change index (non-destructive)
     /// Index is 0-based. Returns error if index is out of bounds.
-    pub fn rollback_to_index(&mut self, target_index: usize) -> Result<(), history::RollbackError> {
-        self.history.rollback_to_index(target_index)?;
-        // Reconstruct the canvas from the truncated history
-        self.canvas = self.history.reconstruct_canvas();
-        Ok(())
+    pub fn rollback_to_index(&mut self, target_index: usize, author: Option<String>) -> Result<(), WorldError> {
+        let timestamp = self.get_current_timestamp();
+        let entry = LogEntry::Rollback { target_index, timestamp, author: author.clone() };
+        self.logged(entry, |world| {
+            world.canvas = world.history.rollback_to_index(target_index, timestamp, author)?;
+            Ok::<_, RollbackError>(())
+        })
+    }
+
+    /// Revert only the paints matching `filter`, recorded as a single change.
+    /// Returns the restored pixels, which is empty if nothing matched.
+    pub fn revert_matching(&mut self, filter: &PaintFilter, author: Option<String>) -> Result<Vec<PixelPaint>, WorldError> {
+        let pixels = self.history.restore_matching(filter);
+        if !pixels.is_empty() {
+            self.apply_event(ChangeEvent::Restore { pixels: pixels.clone() }, author)?;
+        }
+        Ok(pixels)
+    }
+
+    /// Undo the most recent rollback
+    pub fn undo_rollback(&mut self, author: Option<String>) -> Result<(), WorldError> {
+        let timestamp = self.get_current_timestamp();
+        let entry = LogEntry::UndoRollback { timestamp, author: author.clone() };
+        self.logged(entry, |world| {
+            world.canvas = world.history.undo_rollback(timestamp, author)?;
+            Ok::<_, RollbackError>(())
+        })
+    }
+
+    /// Redo the most recently undone rollback
+    pub fn redo_rollback(&mut self, author: Option<String>) -> Result<(), WorldError> {
+        let timestamp = self.get_current_timestamp();
+        let entry = LogEntry::RedoRollback { timestamp, author: author.clone() };
+        self.logged(entry, |world| {
+            world.canvas = world.history.redo_rollback(timestamp, author)?;
+            Ok::<_, RollbackError>(())
+        })
     }
 }
+
+/// Whether paints by `author` start a cooldown the world tracks by itself.
+/// Only session token identities stay the same across connections and restarts;
+/// the server keys everyone else's cooldown through `World::start_cooldown`.
+fn keeps_cooldown(author: &str) -> bool {
+    author.starts_with("user:")
+}
@@ -1,55 +1,81 @@
 use std::collections::HashMap;
+use super::color::Color;
 use serde::{Serialize, Deserialize};
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
 pub struct Palette {
-    colors: Vec<String>,
-    indices: HashMap<String, u32>,
+    colors: Vec<Color>,
+    indices: HashMap<Color, u32>,
 }
 
 impl Palette {
     pub fn new() -> Self {
         let mut palette = Self::default();
         // Start with white as index 0 (default color)
-        palette.add_color("#FFFFFF".to_string());
+        palette.add_color(Color::WHITE);
         palette
     }
-    
+
+    /// Build a palette whose indices are the positions in `colors`
+    pub fn from_colors(colors: Vec<Color>) -> Self {
+        let mut indices = HashMap::with_capacity(colors.len());
+        for (index, &color) in colors.iter().enumerate() {
+            indices.entry(color).or_insert(index as u32);
+        }
+        Palette { colors, indices }
+    }
+
     /// Add a color to the palette if it doesn't exist, return its index
-    pub fn add_color(&mut self, hex: String) -> u32 {
-        if let Some(&index) = self.indices.get(&hex) {
+    pub fn add_color(&mut self, color: Color) -> u32 {
+        if let Some(&index) = self.indices.get(&color) {
             return index;
         }
-        
+
         let index = self.colors.len() as u32;
-        self.colors.push(hex.clone());
-        self.indices.insert(hex, index);
+        self.colors.push(color);
+        self.indices.insert(color, index);
         index
     }
-    
-    #[allow(dead_code)]
-    /// Get the hex string for an index
-    pub fn get_color(&self, index: u32) -> Option<&str> {
-        self.colors.get(index as usize).map(|s| s.as_str())
+
+    /// Build a palette of only the colors flagged in `used`, keeping their order.
+    /// Returns it with the new index of every old index (unused ones map to `u32::MAX`).
+    pub fn retain_used(&self, used: &[bool]) -> (Palette, Vec<u32>) {
+        let mut retained = Vec::new();
+        let mapping = self
+            .colors
+            .iter()
+            .zip(used)
+            .map(|(&color, &used)| {
+                if !used {
+                    return u32::MAX;
+                }
+                retained.push(color);
+                retained.len() as u32 - 1
+            })
+            .collect();
+        (Palette::from_colors(retained), mapping)
     }
-    
-    #[allow(dead_code)]
-    /// Get the index for a hex string
-    pub fn get_index(&self, hex: &str) -> Option<u32> {
-        self.indices.get(hex).copied()
+
+    /// Get the color for an index
+    pub fn get_color(&self, index: u32) -> Option<Color> {
+        self.colors.get(index as usize).copied()
+    }
+
+    /// Get the index for a color
+    pub fn get_index(&self, color: Color) -> Option<u32> {
+        self.indices.get(&color).copied()
     }
-    
+
     /// Get all colors in the palette
-    pub fn colors(&self) -> &[String] {
+    pub fn colors(&self) -> &[Color] {
         &self.colors
     }
-    
-    #[allow(dead_code)]
+
     /// Get the number of colors in the palette
     pub fn len(&self) -> usize {
         self.colors.len()
     }
-    
+
     #[allow(dead_code)]
     pub fn is_empty(&self) -> bool {
         self.colors.is_empty()
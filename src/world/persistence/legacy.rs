@@ -0,0 +1,810 @@
+//! Frozen copies of older history layouts and the migrations that bring them
+//! up to the current format. Types here must never change once released.
+
+use crate::world::canvas::Canvas;
+use crate::world::change::{Change, ChangeEvent, PixelPaint};
+use crate::world::color::Color;
+use crate::world::history::{History, Snapshot, DEFAULT_KEYFRAME_INTERVAL};
+use crate::world::palette::Palette;
+use crate::world::wal::{LogEntry, LogRecord};
+use serde::Deserialize;
+use std::sync::{Arc, RwLock};
+
+/// Version 0: headerless bincode written before the format was versioned, by
+/// servers that kept neither authors nor undo and redo stacks
+mod v0 {
+    use super::Deserialize;
+    use super::hex::{Color, ResizeAnchor};
+
+    #[derive(Deserialize)]
+    pub struct History {
+        pub snapshots: Vec<Snapshot>,
+        pub changes: Vec<Change>,
+        pub snapshot_interval: usize,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Snapshot {
+        pub canvas: Canvas,
+        #[allow(dead_code)]
+        pub change_count: usize,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Canvas {
+        pub width: usize,
+        pub height: usize,
+        /// Palette indices, unusable since the palette was never stored
+        #[allow(dead_code)]
+        pub pixels: Vec<u32>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Change {
+        pub event: ChangeEvent,
+        pub timestamp: u64,
+    }
+
+    #[derive(Deserialize)]
+    pub enum ChangeEvent {
+        Paint {
+            x: usize,
+            y: usize,
+            color: Color,
+        },
+        Resize {
+            anchor: ResizeAnchor,
+            width: usize,
+            height: usize,
+        },
+    }
+}
+
+/// Decode a headerless version 0 file and migrate it to the current layout
+pub fn migrate_v0(data: &[u8]) -> Result<History, Box<dyn std::error::Error>> {
+    let old: v0::History = bincode::deserialize(data)?;
+    let initial = old.snapshots.first().ok_or("Version 0 history has no snapshots")?;
+    if old.snapshot_interval == 0 {
+        return Err("Version 0 history has a zero snapshot interval".into());
+    }
+
+    let changes = old
+        .changes
+        .into_iter()
+        .map(|change| {
+            let event = match change.event {
+                v0::ChangeEvent::Paint { x, y, color } => ChangeEvent::Paint {
+                    x,
+                    y,
+                    color: migrate_color(color)?,
+                },
+                v0::ChangeEvent::Resize { anchor, width, height } => ChangeEvent::Resize {
+                    anchor: anchor.into(),
+                    width,
+                    height,
+                },
+            };
+            Ok(Change {
+                event,
+                timestamp: change.timestamp,
+                author: None,
+            })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    // Stored snapshots are meaningless without their palette, so only the
+    // initial size is kept and the rest are regenerated
+    let canvas = Canvas::new(initial.canvas.width, initial.canvas.height).map_err(|e| format!("{:?}", e))?;
+    let mut history = History::from_parts(
+        vec![Snapshot::keyframe(canvas, 0)],
+        changes,
+        old.snapshot_interval,
+        DEFAULT_KEYFRAME_INTERVAL,
+        Vec::new(),
+        Vec::new(),
+        0,
+    );
+    history.rebuild_snapshots().map_err(|e| format!("{:?}", e))?;
+    Ok(history)
+}
+
+/// Versions 1 to 4: colors were stored as hex strings in changes and the palette
+pub mod hex {
+    use super::Deserialize;
+    use std::collections::HashMap;
+
+    /// Unchanged since version 0
+    #[derive(Deserialize)]
+    pub enum ResizeAnchor {
+        TopLeft,
+        TopRight,
+        BottomLeft,
+        BottomRight,
+        Center,
+    }
+
+    impl From<ResizeAnchor> for crate::world::change::ResizeAnchor {
+        fn from(old: ResizeAnchor) -> Self {
+            match old {
+                ResizeAnchor::TopLeft => Self::TopLeft,
+                ResizeAnchor::TopRight => Self::TopRight,
+                ResizeAnchor::BottomLeft => Self::BottomLeft,
+                ResizeAnchor::BottomRight => Self::BottomRight,
+                ResizeAnchor::Center => Self::Center,
+            }
+        }
+    }
+
+    /// Unchanged since version 0
+    #[derive(Deserialize)]
+    pub struct Color {
+        pub hex: String,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Palette {
+        pub colors: Vec<String>,
+        #[allow(dead_code)]
+        pub indices: HashMap<String, u32>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Change {
+        pub event: ChangeEvent,
+        pub timestamp: u64,
+        pub author: Option<String>,
+    }
+
+    #[derive(Deserialize)]
+    pub enum ChangeEvent {
+        Paint {
+            x: usize,
+            y: usize,
+            color: Color,
+        },
+        Resize {
+            anchor: ResizeAnchor,
+            width: usize,
+            height: usize,
+        },
+        Revert {
+            target_index: usize,
+        },
+        Restore {
+            pixels: Vec<PixelPaint>,
+        },
+        Import {
+            x: usize,
+            y: usize,
+            width: usize,
+            height: usize,
+            pixels: Vec<Option<Color>>,
+        },
+    }
+
+    #[derive(Deserialize)]
+    pub struct PixelPaint {
+        pub x: usize,
+        pub y: usize,
+        pub color: Color,
+    }
+
+    /// Write-ahead log version 1 entries
+    #[derive(Deserialize)]
+    pub enum LogEntry {
+        Change(Change),
+        Rollback {
+            target_index: usize,
+            timestamp: u64,
+            author: Option<String>,
+        },
+        UndoRollback {
+            timestamp: u64,
+            author: Option<String>,
+        },
+        RedoRollback {
+            timestamp: u64,
+            author: Option<String>,
+        },
+    }
+}
+
+fn migrate_color(color: hex::Color) -> Result<Color, String> {
+    Color::from_hex(&color.hex)
+}
+
+fn migrate_palette(palette: hex::Palette) -> Result<Palette, String> {
+    let colors = palette.colors.iter().map(|hex| Color::from_hex(hex)).collect::<Result<_, _>>()?;
+    Ok(Palette::from_colors(colors))
+}
+
+fn migrate_change(change: hex::Change) -> Result<Change, String> {
+    let event = match change.event {
+        hex::ChangeEvent::Paint { x, y, color } => ChangeEvent::Paint {
+            x,
+            y,
+            color: migrate_color(color)?,
+        },
+        hex::ChangeEvent::Resize { anchor, width, height } => ChangeEvent::Resize {
+            anchor: anchor.into(),
+            width,
+            height,
+        },
+        hex::ChangeEvent::Revert { target_index } => ChangeEvent::Revert { target_index },
+        hex::ChangeEvent::Restore { pixels } => ChangeEvent::Restore {
+            pixels: pixels
+                .into_iter()
+                .map(|p| Ok(PixelPaint { x: p.x, y: p.y, color: migrate_color(p.color)? }))
+                .collect::<Result<_, String>>()?,
+        },
+        hex::ChangeEvent::Import { x, y, width, height, pixels } => ChangeEvent::Import {
+            x,
+            y,
+            width,
+            height,
+            pixels: pixels
+                .into_iter()
+                .map(|color| color.map(migrate_color).transpose())
+                .collect::<Result<_, _>>()?,
+        },
+    };
+    Ok(Change {
+        event,
+        timestamp: change.timestamp,
+        author: change.author,
+    })
+}
+
+fn migrate_changes(changes: Vec<hex::Change>) -> Result<Vec<Change>, String> {
+    changes.into_iter().map(migrate_change).collect()
+}
+
+/// Decode a record body from a version 1 write-ahead log
+pub fn migrate_log_record_v1(body: &[u8]) -> Result<LogRecord, Box<dyn std::error::Error>> {
+    let (index, entry): (usize, hex::LogEntry) = bincode::deserialize(body)?;
+    let entry = match entry {
+        hex::LogEntry::Change(change) => LogEntry::Change(migrate_change(change)?),
+        hex::LogEntry::Rollback { target_index, timestamp, author } => LogEntry::Rollback { target_index, timestamp, author },
+        hex::LogEntry::UndoRollback { timestamp, author } => LogEntry::UndoRollback { timestamp, author },
+        hex::LogEntry::RedoRollback { timestamp, author } => LogEntry::RedoRollback { timestamp, author },
+    };
+    Ok((index, entry))
+}
+
+/// Versions 1 and 2: the `History` layout before changes could be pruned
+mod v2 {
+    use super::hex::Change;
+    use crate::world::palette::Palette;
+    use serde::Deserialize;
+    use std::sync::{Arc, RwLock};
+
+    #[derive(Deserialize)]
+    pub struct History {
+        pub snapshots: Vec<Snapshot>,
+        pub changes: Vec<Change>,
+        pub snapshot_interval: usize,
+        pub undo_stack: Vec<usize>,
+        pub redo_stack: Vec<usize>,
+    }
+
+    /// Every snapshot held a full canvas before they were delta-encoded
+    #[derive(Deserialize)]
+    pub struct Snapshot {
+        pub canvas: Canvas,
+        pub change_count: usize,
+    }
+
+    /// Palette indices, with the palette stored separately since version 2
+    #[derive(Deserialize)]
+    pub struct Canvas {
+        pub width: usize,
+        pub height: usize,
+        pub pixels: Vec<u32>,
+    }
+
+    impl TryFrom<Canvas> for crate::world::canvas::Canvas {
+        type Error = String;
+
+        /// The palette is filled in once the whole history has been migrated
+        fn try_from(old: Canvas) -> Result<Self, String> {
+            Self::from_pixels(old.width, old.height, old.pixels, Arc::new(RwLock::new(Palette::new())))
+                .map_err(|e| format!("{:?}", e))
+        }
+    }
+
+    impl TryFrom<Snapshot> for crate::world::history::Snapshot {
+        type Error = String;
+
+        fn try_from(old: Snapshot) -> Result<Self, String> {
+            Ok(Self::keyframe(old.canvas.try_into()?, old.change_count))
+        }
+    }
+
+    pub fn migrate_snapshots(snapshots: Vec<Snapshot>) -> Result<Vec<crate::world::history::Snapshot>, String> {
+        snapshots.into_iter().map(TryInto::try_into).collect()
+    }
+
+    impl TryFrom<History> for super::History {
+        type Error = String;
+
+        fn try_from(old: History) -> Result<Self, String> {
+            Ok(super::History::from_parts(
+                migrate_snapshots(old.snapshots)?,
+                super::migrate_changes(old.changes)?,
+                old.snapshot_interval,
+                super::DEFAULT_KEYFRAME_INTERVAL,
+                old.undo_stack,
+                old.redo_stack,
+                0,
+            ))
+        }
+    }
+}
+
+/// Version 3: full-canvas snapshots with pruned changes
+mod v3 {
+    use super::hex::Change;
+    use super::v2::Snapshot;
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    pub struct History {
+        pub snapshots: Vec<Snapshot>,
+        pub changes: Vec<Change>,
+        pub snapshot_interval: usize,
+        pub undo_stack: Vec<usize>,
+        pub redo_stack: Vec<usize>,
+        pub first_index: usize,
+    }
+
+    impl TryFrom<History> for super::History {
+        type Error = String;
+
+        fn try_from(old: History) -> Result<Self, String> {
+            Ok(super::History::from_parts(
+                super::v2::migrate_snapshots(old.snapshots)?,
+                super::migrate_changes(old.changes)?,
+                old.snapshot_interval,
+                super::DEFAULT_KEYFRAME_INTERVAL,
+                old.undo_stack,
+                old.redo_stack,
+                old.first_index,
+            ))
+        }
+    }
+}
+
+/// Version 4: delta-encoded snapshots, still with hex string colors
+mod v4 {
+    use super::hex::Change;
+    use super::v2::Canvas;
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    pub struct History {
+        pub snapshots: Vec<Snapshot>,
+        pub changes: Vec<Change>,
+        pub snapshot_interval: usize,
+        pub keyframe_interval: usize,
+        pub undo_stack: Vec<usize>,
+        pub redo_stack: Vec<usize>,
+        pub first_index: usize,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Snapshot {
+        pub change_count: usize,
+        pub data: SnapshotData,
+    }
+
+    #[derive(Deserialize)]
+    pub enum SnapshotData {
+        Keyframe(Canvas),
+        Delta(Vec<(u32, u32)>),
+    }
+
+    impl TryFrom<Snapshot> for crate::world::history::Snapshot {
+        type Error = String;
+
+        fn try_from(old: Snapshot) -> Result<Self, String> {
+            Ok(match old.data {
+                SnapshotData::Keyframe(canvas) => Self::keyframe(canvas.try_into()?, old.change_count),
+                SnapshotData::Delta(diff) => Self::delta(diff, old.change_count),
+            })
+        }
+    }
+
+    /// Check the chain starts with a keyframe, which every delta builds on
+    pub fn migrate_snapshots(snapshots: Vec<Snapshot>) -> Result<Vec<crate::world::history::Snapshot>, String> {
+        if !snapshots.first().is_some_and(|snapshot| matches!(snapshot.data, SnapshotData::Keyframe(_))) {
+            return Err("History does not start with a keyframe snapshot".to_string());
+        }
+        snapshots.into_iter().map(TryInto::try_into).collect()
+    }
+
+    impl TryFrom<History> for super::History {
+        type Error = String;
+
+        fn try_from(old: History) -> Result<Self, String> {
+            Ok(super::History::from_parts(
+                migrate_snapshots(old.snapshots)?,
+                super::migrate_changes(old.changes)?,
+                old.snapshot_interval,
+                old.keyframe_interval,
+                old.undo_stack,
+                old.redo_stack,
+                old.first_index,
+            ))
+        }
+    }
+}
+
+/// Version 5: packed colors, before canvases could restrict their palette
+mod v5 {
+    use super::hex::ResizeAnchor;
+    use super::v4::Snapshot;
+    use serde::Deserialize;
+    use std::collections::HashMap;
+
+    #[derive(Deserialize)]
+    pub struct History {
+        pub snapshots: Vec<Snapshot>,
+        pub changes: Vec<Change>,
+        pub snapshot_interval: usize,
+        pub keyframe_interval: usize,
+        pub undo_stack: Vec<usize>,
+        pub redo_stack: Vec<usize>,
+        pub first_index: usize,
+    }
+
+    /// RGBA packed as 0xRRGGBBAA
+    #[derive(Deserialize, PartialEq, Eq, Hash)]
+    pub struct Color(pub u32);
+
+    #[derive(Deserialize)]
+    pub struct Palette {
+        pub colors: Vec<Color>,
+        #[allow(dead_code)]
+        pub indices: HashMap<Color, u32>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Change {
+        pub event: ChangeEvent,
+        pub timestamp: u64,
+        pub author: Option<String>,
+    }
+
+    #[derive(Deserialize)]
+    pub enum ChangeEvent {
+        Paint {
+            x: usize,
+            y: usize,
+            color: Color,
+        },
+        Resize {
+            anchor: ResizeAnchor,
+            width: usize,
+            height: usize,
+        },
+        Revert {
+            target_index: usize,
+        },
+        Restore {
+            pixels: Vec<PixelPaint>,
+        },
+        Import {
+            x: usize,
+            y: usize,
+            width: usize,
+            height: usize,
+            pixels: Vec<Option<Color>>,
+        },
+    }
+
+    #[derive(Deserialize)]
+    pub struct PixelPaint {
+        pub x: usize,
+        pub y: usize,
+        pub color: Color,
+    }
+
+    impl From<Color> for crate::world::color::Color {
+        fn from(old: Color) -> Self {
+            Self::from_rgba(old.0.to_be_bytes())
+        }
+    }
+
+    impl From<Palette> for crate::world::palette::Palette {
+        fn from(old: Palette) -> Self {
+            Self::from_colors(old.colors.into_iter().map(Into::into).collect())
+        }
+    }
+
+    impl From<PixelPaint> for crate::world::change::PixelPaint {
+        fn from(old: PixelPaint) -> Self {
+            Self {
+                x: old.x,
+                y: old.y,
+                color: old.color.into(),
+            }
+        }
+    }
+
+    impl From<ChangeEvent> for crate::world::change::ChangeEvent {
+        fn from(old: ChangeEvent) -> Self {
+            match old {
+                ChangeEvent::Paint { x, y, color } => Self::Paint { x, y, color: color.into() },
+                ChangeEvent::Resize { anchor, width, height } => Self::Resize {
+                    anchor: anchor.into(),
+                    width,
+                    height,
+                },
+                ChangeEvent::Revert { target_index } => Self::Revert { target_index },
+                ChangeEvent::Restore { pixels } => Self::Restore {
+                    pixels: pixels.into_iter().map(Into::into).collect(),
+                },
+                ChangeEvent::Import { x, y, width, height, pixels } => Self::Import {
+                    x,
+                    y,
+                    width,
+                    height,
+                    pixels: pixels.into_iter().map(|color| color.map(Into::into)).collect(),
+                },
+            }
+        }
+    }
+
+    impl From<Change> for crate::world::change::Change {
+        fn from(old: Change) -> Self {
+            Self {
+                event: old.event.into(),
+                timestamp: old.timestamp,
+                author: old.author,
+            }
+        }
+    }
+
+    impl TryFrom<History> for super::History {
+        type Error = String;
+
+        fn try_from(old: History) -> Result<Self, String> {
+            Ok(super::History::from_parts(
+                super::v4::migrate_snapshots(old.snapshots)?,
+                old.changes.into_iter().map(Into::into).collect(),
+                old.snapshot_interval,
+                old.keyframe_interval,
+                old.undo_stack,
+                old.redo_stack,
+                old.first_index,
+            ))
+        }
+    }
+}
+
+/// Version 6: restricted palettes, before per-canvas paint cooldowns
+mod v6 {
+    use super::hex::ResizeAnchor;
+    use super::v4::Snapshot;
+    use super::v5::{Color, PixelPaint};
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    pub struct History {
+        pub snapshots: Vec<Snapshot>,
+        pub changes: Vec<Change>,
+        pub snapshot_interval: usize,
+        pub keyframe_interval: usize,
+        pub undo_stack: Vec<usize>,
+        pub redo_stack: Vec<usize>,
+        pub first_index: usize,
+        pub allowed_colors: Option<Vec<Color>>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Change {
+        pub event: ChangeEvent,
+        pub timestamp: u64,
+        pub author: Option<String>,
+    }
+
+    /// Version 5 events plus `SetPalette`
+    #[derive(Deserialize)]
+    pub enum ChangeEvent {
+        Paint {
+            x: usize,
+            y: usize,
+            color: Color,
+        },
+        Resize {
+            anchor: ResizeAnchor,
+            width: usize,
+            height: usize,
+        },
+        Revert {
+            target_index: usize,
+        },
+        Restore {
+            pixels: Vec<PixelPaint>,
+        },
+        Import {
+            x: usize,
+            y: usize,
+            width: usize,
+            height: usize,
+            pixels: Vec<Option<Color>>,
+        },
+        SetPalette {
+            colors: Option<Vec<Color>>,
+        },
+    }
+
+    impl From<ChangeEvent> for crate::world::change::ChangeEvent {
+        fn from(old: ChangeEvent) -> Self {
+            match old {
+                ChangeEvent::Paint { x, y, color } => Self::Paint { x, y, color: color.into() },
+                ChangeEvent::Resize { anchor, width, height } => Self::Resize {
+                    anchor: anchor.into(),
+                    width,
+                    height,
+                },
+                ChangeEvent::Revert { target_index } => Self::Revert { target_index },
+                ChangeEvent::Restore { pixels } => Self::Restore {
+                    pixels: pixels.into_iter().map(Into::into).collect(),
+                },
+                ChangeEvent::Import { x, y, width, height, pixels } => Self::Import {
+                    x,
+                    y,
+                    width,
+                    height,
+                    pixels: pixels.into_iter().map(|color| color.map(Into::into)).collect(),
+                },
+                ChangeEvent::SetPalette { colors } => Self::SetPalette {
+                    colors: colors.map(|colors| colors.into_iter().map(Into::into).collect()),
+                },
+            }
+        }
+    }
+
+    impl From<Change> for crate::world::change::Change {
+        fn from(old: Change) -> Self {
+            Self {
+                event: old.event.into(),
+                timestamp: old.timestamp,
+                author: old.author,
+            }
+        }
+    }
+
+    /// Colors a restricted palette allows, in palette index order
+    pub fn migrate_allowed_colors(colors: Option<Vec<Color>>) -> Option<Vec<crate::world::color::Color>> {
+        colors.map(|colors| colors.into_iter().map(Into::into).collect())
+    }
+
+    impl TryFrom<History> for super::History {
+        type Error = String;
+
+        fn try_from(old: History) -> Result<Self, String> {
+            let mut history = super::History::from_parts(
+                super::v4::migrate_snapshots(old.snapshots)?,
+                old.changes.into_iter().map(Into::into).collect(),
+                old.snapshot_interval,
+                old.keyframe_interval,
+                old.undo_stack,
+                old.redo_stack,
+                old.first_index,
+            );
+            history.set_allowed_colors(migrate_allowed_colors(old.allowed_colors));
+            Ok(history)
+        }
+    }
+}
+
+/// Version 7: per-canvas paint cooldowns, before bans
+mod v7 {
+    use super::v4::Snapshot;
+    use super::v5::Color;
+    use super::v6::Change;
+    use serde::Deserialize;
+    use std::collections::BTreeMap;
+
+    #[derive(Deserialize)]
+    pub struct History {
+        pub snapshots: Vec<Snapshot>,
+        pub changes: Vec<Change>,
+        pub snapshot_interval: usize,
+        pub keyframe_interval: usize,
+        pub undo_stack: Vec<usize>,
+        pub redo_stack: Vec<usize>,
+        pub first_index: usize,
+        pub allowed_colors: Option<Vec<Color>>,
+        pub cooldowns: BTreeMap<String, u64>,
+    }
+
+    impl TryFrom<History> for super::History {
+        type Error = String;
+
+        fn try_from(old: History) -> Result<Self, String> {
+            let mut history = super::History::from_parts(
+                super::v4::migrate_snapshots(old.snapshots)?,
+                old.changes.into_iter().map(Into::into).collect(),
+                old.snapshot_interval,
+                old.keyframe_interval,
+                old.undo_stack,
+                old.redo_stack,
+                old.first_index,
+            );
+            history.set_allowed_colors(super::v6::migrate_allowed_colors(old.allowed_colors));
+            for (role, cooldown_ms) in old.cooldowns {
+                history.set_cooldown(&role, Some(cooldown_ms));
+            }
+            Ok(history)
+        }
+    }
+}
+
+/// Version 1 stored no palette, so the snapshots are regenerated to recover
+/// the colors their indices refer to
+pub fn migrate_v1(body: &[u8]) -> Result<History, Box<dyn std::error::Error>> {
+    let old: v2::History = bincode::deserialize(body)?;
+    if old.snapshots.is_empty() {
+        return Err("History has no snapshots".into());
+    }
+    let mut history = History::try_from(old)?;
+    history.rebuild_snapshots().map_err(|e| format!("{:?}", e))?;
+    Ok(history)
+}
+
+/// Version 2 stored the palette ahead of a history that could not be pruned
+pub fn migrate_v2(body: &[u8]) -> Result<History, Box<dyn std::error::Error>> {
+    let (palette, old): (hex::Palette, v2::History) = bincode::deserialize(body)?;
+    if old.snapshots.is_empty() {
+        return Err("History has no snapshots".into());
+    }
+    let mut history = History::try_from(old)?;
+    history.set_palette(Arc::new(RwLock::new(migrate_palette(palette)?)));
+    Ok(history)
+}
+
+/// Version 3 stored every snapshot as a full canvas; they are re-encoded as deltas
+pub fn migrate_v3(body: &[u8]) -> Result<History, Box<dyn std::error::Error>> {
+    let (palette, old): (hex::Palette, v3::History) = bincode::deserialize(body)?;
+    if old.snapshots.is_empty() {
+        return Err("History has no snapshots".into());
+    }
+    let mut history = History::try_from(old)?;
+    history.set_palette(Arc::new(RwLock::new(migrate_palette(palette)?)));
+    Ok(history)
+}
+
+/// Version 4 stored colors as hex strings
+pub fn migrate_v4(body: &[u8]) -> Result<History, Box<dyn std::error::Error>> {
+    let (palette, old): (hex::Palette, v4::History) = bincode::deserialize(body)?;
+    let mut history = History::try_from(old)?;
+    history.set_palette(Arc::new(RwLock::new(migrate_palette(palette)?)));
+    Ok(history)
+}
+
+/// Version 5 had no restricted palette
+pub fn migrate_v5(body: &[u8]) -> Result<History, Box<dyn std::error::Error>> {
+    let (palette, old): (v5::Palette, v5::History) = bincode::deserialize(body)?;
+    let mut history = History::try_from(old)?;
+    history.set_palette(Arc::new(RwLock::new(palette.into())));
+    Ok(history)
+}
+
+/// Version 6 had no per-canvas paint cooldowns
+pub fn migrate_v6(body: &[u8]) -> Result<History, Box<dyn std::error::Error>> {
+    let (palette, old): (v5::Palette, v6::History) = bincode::deserialize(body)?;
+    let mut history = History::try_from(old)?;
+    history.set_palette(Arc::new(RwLock::new(palette.into())));
+    Ok(history)
+}
+
+/// Version 7 had no per-canvas bans
+pub fn migrate_v7(body: &[u8]) -> Result<History, Box<dyn std::error::Error>> {
+    let (palette, old): (v5::Palette, v7::History) = bincode::deserialize(body)?;
+    let mut history = History::try_from(old)?;
+    history.set_palette(Arc::new(RwLock::new(palette.into())));
+    Ok(history)
+}
@@ -0,0 +1,241 @@
+pub(super) mod legacy;
+
+use super::history::History;
+use super::palette::Palette;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::sync::{Arc, RwLock};
+
+/// Magic bytes at the start of every versioned history file
+const MAGIC: &[u8; 8] = b"PIXIEHST";
+
+/// Layout version written by `save_history`. Bump it whenever a persisted type
+/// changes, freezing the previous layout in `legacy` with a migration.
+pub const FORMAT_VERSION: u32 = 8;
+
+/// Save history to disk using binary format with atomic write
+pub fn save_history(history: &History, history_file: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let temp_file = format!("{}.tmp", history_file);
+    let backup_file = format!("{}.bak", history_file);
+    
+    let serialized = encode_history(history)?;
+    
+    // Write to temporary file
+    let mut file = File::create(&temp_file)?;
+    file.write_all(&serialized)?;
+    file.sync_all()?; // Ensure data is written to disk
+    drop(file);
+    
+    // Create backup of existing file if it exists
+    if std::path::Path::new(history_file).exists() {
+        std::fs::copy(history_file, &backup_file)?;
+    }
+    
+    // Atomic rename
+    std::fs::rename(&temp_file, history_file)?;
+    
+    Ok(())
+}
+
+/// Load history from disk, migrating older formats
+pub fn load_history(history_file: &str) -> Result<History, Box<dyn std::error::Error>> {
+    let mut file = File::open(history_file)?;
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer)?;
+    decode_history(&buffer)
+}
+
+/// Serialize history behind the magic bytes and format version, followed by
+/// the palette its snapshots share and the history itself
+pub fn encode_history(history: &History) -> Result<Vec<u8>, bincode::Error> {
+    let mut buffer = MAGIC.to_vec();
+    buffer.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    let palette = history.palette();
+    let palette = palette.read().unwrap();
+    bincode::serialize_into(&mut buffer, &(&*palette, history))?;
+    Ok(buffer)
+}
+
+/// Deserialize history written by any known format version
+pub fn decode_history(data: &[u8]) -> Result<History, Box<dyn std::error::Error>> {
+    let Some(body) = data.strip_prefix(MAGIC) else {
+        // Files written before the header was introduced
+        return legacy::migrate_v0(data)
+            .map_err(|e| format!("Not a history file, or an unreadable unversioned one: {}", e).into());
+    };
+    let (version, body) = body.split_first_chunk::<4>().ok_or("History header is truncated")?;
+
+    match u32::from_le_bytes(*version) {
+        FORMAT_VERSION => {
+            let (palette, mut history): (Palette, History) = bincode::deserialize(body)?;
+            if !history.snapshots.first().is_some_and(|snapshot| snapshot.is_keyframe()) {
+                return Err("History does not start with a keyframe snapshot".into());
+            }
+            history.set_palette(Arc::new(RwLock::new(palette)));
+            Ok(history)
+        }
+        1 => legacy::migrate_v1(body),
+        2 => legacy::migrate_v2(body),
+        3 => legacy::migrate_v3(body),
+        4 => legacy::migrate_v4(body),
+        5 => legacy::migrate_v5(body),
+        6 => legacy::migrate_v6(body),
+        7 => legacy::migrate_v7(body),
+        version if version > FORMAT_VERSION => {
+            Err(format!("History format version {} is newer than supported version {}", version, FORMAT_VERSION).into())
+        }
+        version => Err(format!("Unknown history format version {}", version).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::World;
+    use crate::world::change::{ChangeEvent, ResizeAnchor};
+    use crate::world::color::Color;
+    use crate::world::image::canvas_rgb;
+
+    /// History files written by the server at each format version, starting
+    /// from an 8x8 canvas: ten paints along the top rows, a resize to 10x6, a
+    /// paint, a rollback to change 6 and a paint at (7, 5). The baseline server
+    /// behind version 0 could only roll back by discarding changes, so that
+    /// fixture skips the rollback. From version 6 on a restricted palette is
+    /// then set and (1, 1) painted black, and version 7 also has a 1500 ms
+    /// player cooldown.
+    const FIXTURES: [(u32, &[u8]); 8] = [
+        (0, include_bytes!("fixtures/v0.bin")),
+        (1, include_bytes!("fixtures/v1.bin")),
+        (2, include_bytes!("fixtures/v2.bin")),
+        (3, include_bytes!("fixtures/v3.bin")),
+        (4, include_bytes!("fixtures/v4.bin")),
+        (5, include_bytes!("fixtures/v5.bin")),
+        (6, include_bytes!("fixtures/v6.bin")),
+        (7, include_bytes!("fixtures/v7.bin")),
+    ];
+
+    fn color(hex: &str) -> Color {
+        Color::from_hex(hex).unwrap()
+    }
+
+    /// Every version in `FIXTURES` is read through the header it was written with
+    #[test]
+    fn fixtures_carry_their_format_version() {
+        for (version, data) in FIXTURES {
+            match data.strip_prefix(MAGIC) {
+                Some(body) => assert_eq!(body[..4], version.to_le_bytes(), "fixture v{}", version),
+                None => assert_eq!(version, 0, "only version 0 files are headerless"),
+            }
+        }
+    }
+
+    #[test]
+    fn fixtures_migrate_to_the_canvas_they_were_written_with() {
+        let colors = ["#FF0000", "#00FF00", "#0000FF", "#123456", "#ABCDEF"];
+        for (version, data) in FIXTURES {
+            let world = World::from(decode_history(data).unwrap_or_else(|e| panic!("fixture v{}: {}", version, e)));
+            let painted = world.history.canvas_at(10);
+            for x in 0..8 {
+                assert_eq!(painted.get_pixel(x, 0).unwrap(), color(colors[x % colors.len()]), "fixture v{}", version);
+            }
+
+            if version == 0 {
+                assert_eq!(world.change_count(), 13);
+                assert_eq!(world.dimensions(), (10, 6));
+                assert_eq!(world.canvas.get_pixel(9, 5).unwrap(), color("#FEDCBA"));
+                assert_eq!(world.canvas.get_pixel(7, 5).unwrap(), color("#00FFFF"));
+                assert_eq!(world.history.allowed_colors(), None);
+                continue;
+            }
+
+            let restricted = version >= 6;
+
+            assert_eq!(world.change_count(), if restricted { 16 } else { 14 }, "fixture v{}", version);
+            assert_eq!(world.dimensions(), (8, 8), "fixture v{}", version);
+            for x in 0..7 {
+                assert_eq!(world.canvas.get_pixel(x, 0).unwrap(), color(colors[x % colors.len()]), "fixture v{}", version);
+            }
+            assert_eq!(world.canvas.get_pixel(7, 0).unwrap(), Color::WHITE, "fixture v{}", version);
+            assert_eq!(world.canvas.get_pixel(7, 5).unwrap(), color("#00FFFF"), "fixture v{}", version);
+
+            let resized = world.history.canvas_at(12);
+            assert_eq!((resized.width(), resized.height()), (10, 6), "fixture v{}", version);
+            assert_eq!(resized.get_pixel(9, 5).unwrap(), color("#FEDCBA"), "fixture v{}", version);
+
+            if restricted {
+                assert_eq!(world.canvas.get_pixel(1, 1).unwrap(), color("#000000"), "fixture v{}", version);
+                let allowed = [Color::WHITE, color("#000000"), color("#FF0000")];
+                assert_eq!(world.history.allowed_colors(), Some(&allowed[..]), "fixture v{}", version);
+            } else {
+                assert_eq!(world.history.allowed_colors(), None, "fixture v{}", version);
+            }
+            let cooldown = (version >= 7).then_some(1500);
+            assert_eq!(world.history.cooldown("player"), cooldown, "fixture v{}", version);
+        }
+    }
+
+    #[test]
+    fn migrated_fixtures_round_trip_through_the_current_format() {
+        for (version, data) in FIXTURES {
+            let migrated = decode_history(data).unwrap();
+            let encoded = encode_history(&migrated).unwrap();
+            assert_eq!(encoded[MAGIC.len()..MAGIC.len() + 4], FORMAT_VERSION.to_le_bytes());
+
+            let reloaded = decode_history(&encoded).unwrap();
+            assert_eq!(reloaded.current_change_count(), migrated.current_change_count(), "fixture v{}", version);
+            for count in 0..=migrated.current_change_count() {
+                let (expected, actual) = (migrated.canvas_at(count), reloaded.canvas_at(count));
+                assert_eq!(
+                    canvas_rgb(&actual),
+                    canvas_rgb(&expected),
+                    "fixture v{} differs after {} changes",
+                    version,
+                    count
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn unreadable_headers_are_refused() {
+        let mut newer = MAGIC.to_vec();
+        newer.extend_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        let error = decode_history(&newer).err().unwrap().to_string();
+        assert!(error.contains("newer than supported"), "{}", error);
+
+        let truncated = [&MAGIC[..], &[1, 0]].concat();
+        assert!(decode_history(&truncated).is_err());
+        assert!(decode_history(b"not a history file").is_err());
+
+        // A current header over a body from another version
+        let mut mislabeled = MAGIC.to_vec();
+        mislabeled.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        mislabeled.extend_from_slice(&FIXTURES[1].1[MAGIC.len() + 4..]);
+        assert!(decode_history(&mislabeled).is_err());
+    }
+
+    #[test]
+    fn reloaded_world_renders_identically() {
+        let mut world = World::new(8, 8, 4).unwrap();
+        let colors = ["#FF0000", "#00FF00", "#0000FF", "#123456", "#ABCDEF"];
+        for i in 0..10 {
+            let color = Color::from_hex(colors[i % colors.len()]).unwrap();
+            world.apply_event(ChangeEvent::Paint { x: i % 8, y: i / 8, color }, None).unwrap();
+        }
+        world.apply_event(ChangeEvent::Resize { anchor: ResizeAnchor::Center, width: 10, height: 6 }, None).unwrap();
+        world.rollback_to_index(6, None).unwrap();
+        let color = Color::from_hex("#FEDCBA").unwrap();
+        world.apply_event(ChangeEvent::Paint { x: 7, y: 7, color }, None).unwrap();
+
+        let encoded = encode_history(&world.history).unwrap();
+        let reloaded = World::from(decode_history(&encoded).unwrap());
+
+        assert_eq!(canvas_rgb(&reloaded.canvas), canvas_rgb(&world.canvas));
+        for count in 0..=world.change_count() {
+            let expected = world.history.canvas_at(count);
+            let actual = reloaded.history.canvas_at(count);
+            assert_eq!((actual.width(), actual.height()), (expected.width(), expected.height()));
+            assert_eq!(canvas_rgb(&actual), canvas_rgb(&expected), "canvas differs after {} changes", count);
+        }
+    }
+}
@@ -0,0 +1,384 @@
+use super::change::Change;
+use super::persistence::legacy;
+use serde::{Serialize, Deserialize};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Magic bytes at the start of every write-ahead log
+const MAGIC: &[u8; 8] = b"PIXIEWAL";
+
+/// Layout version of log records; bump it whenever `LogEntry` or `Change` changes
+const FORMAT_VERSION: u32 = 3;
+
+const HEADER_LEN: usize = MAGIC.len() + 4;
+
+/// An operation recorded in the write-ahead log. Rollbacks are logged as
+/// operations rather than plain changes so replaying them also rebuilds the
+/// undo and redo stacks. Room settings are logged too, though they don't add
+/// to the change count.
+#[derive(Serialize, Deserialize)]
+pub enum LogEntry {
+    Change(Change),
+    Rollback {
+        target_index: usize,
+        timestamp: u64,
+        author: Option<String>,
+    },
+    UndoRollback {
+        timestamp: u64,
+        author: Option<String>,
+    },
+    RedoRollback {
+        timestamp: u64,
+        author: Option<String>,
+    },
+    SetCooldown {
+        role: String,
+        cooldown_ms: Option<u64>,
+    },
+    Ban {
+        identity: String,
+        until_ms: Option<u64>,
+        timestamp: u64,
+    },
+    Unban {
+        identity: String,
+    },
+}
+
+/// A logged entry together with the number of changes that existed before it,
+/// so entries already folded into the snapshot file can be skipped on replay
+pub type LogRecord = (usize, LogEntry);
+
+/// Decodes the body of one record in a particular format version
+type RecordDecoder = fn(&[u8]) -> Result<LogRecord, Box<dyn std::error::Error>>;
+
+/// Append-only log of the operations applied since the last compaction.
+/// Each record is a little-endian u32 length followed by a bincode `LogRecord`.
+pub struct WriteAheadLog {
+    file: File,
+    /// Byte offset just past the last complete record
+    end: u64,
+    /// Offset the most recent record starts at, until it is retracted
+    last_start: Option<u64>,
+    /// Handle for making written records durable
+    syncer: Arc<LogSyncer>,
+    /// Records in the log since the last truncation
+    len: usize,
+}
+
+impl WriteAheadLog {
+    /// Open the log at `path` for appending, returning the records it already holds.
+    /// A partially written record at the end, left by a crash, is discarded, and a
+    /// log in an older format is rewritten in the current one.
+    pub fn open(path: &str) -> Result<(Self, Vec<LogRecord>), Box<dyn std::error::Error>> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        let mut buffer = Vec::new();
+        file.read_to_end(&mut buffer)?;
+
+        if buffer.is_empty() {
+            file.write_all(&header())?;
+            file.sync_all()?;
+            buffer = header();
+        }
+        let (records, valid_len, version) = decode_log(&buffer).map_err(|e| format!("{}: {}", path, e))?;
+        if version != FORMAT_VERSION {
+            println!("Migrating write-ahead log {} from format version {}", path, version);
+            file = rewrite(path, &records)?;
+        } else if valid_len < buffer.len() {
+            eprintln!("Discarding {} trailing byte(s) of incomplete log record in {}", buffer.len() - valid_len, path);
+            file.set_len(valid_len as u64)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::Start(valid_len as u64))?;
+
+        let syncer = Arc::new(LogSyncer {
+            file: file.try_clone()?,
+            dirty: AtomicBool::new(false),
+        });
+        let log = WriteAheadLog {
+            file,
+            end: valid_len as u64,
+            last_start: None,
+            syncer,
+            len: records.len(),
+        };
+        Ok((log, records))
+    }
+
+    /// Write a record; it becomes durable on the next `sync`. If the write fails,
+    /// whatever part of it reached the file is cut off again, so later records
+    /// never follow a broken one.
+    pub fn append(&mut self, change_index: usize, entry: &LogEntry) -> Result<(), Box<dyn std::error::Error>> {
+        let encoded = bincode::serialize(&(change_index, entry))?;
+        let mut record = (encoded.len() as u32).to_le_bytes().to_vec();
+        record.extend_from_slice(&encoded);
+
+        if let Err(e) = self.file.write_all(&record) {
+            self.cut(self.end)?;
+            return Err(e.into());
+        }
+        self.last_start = Some(self.end);
+        self.end += record.len() as u64;
+        self.syncer.dirty.store(true, Ordering::Release);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Remove the record just appended, for an operation that turned out not to apply
+    pub fn retract(&mut self) -> std::io::Result<()> {
+        let Some(start) = self.last_start.take() else {
+            return Ok(());
+        };
+        self.cut(start)?;
+        self.len -= 1;
+        Ok(())
+    }
+
+    /// A handle that fsyncs this log without needing access to it
+    pub fn syncer(&self) -> Arc<LogSyncer> {
+        self.syncer.clone()
+    }
+
+    /// Drop every record, once they have been compacted into the snapshot file
+    pub fn truncate(&mut self) -> std::io::Result<()> {
+        self.cut(HEADER_LEN as u64)?;
+        self.file.sync_all()?;
+        self.last_start = None;
+        self.len = 0;
+        Ok(())
+    }
+
+    /// Shorten the file to `end` bytes and continue writing from there
+    fn cut(&mut self, end: u64) -> std::io::Result<()> {
+        self.file.set_len(end)?;
+        self.file.seek(SeekFrom::Start(end))?;
+        self.end = end;
+        Ok(())
+    }
+
+    /// Whether the log holds no records since the last truncation
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// Fsyncs a write-ahead log through its own file handle, so records can be
+/// made durable while the log keeps accepting new ones
+pub struct LogSyncer {
+    file: File,
+    /// Whether records were written since the last sync
+    dirty: AtomicBool,
+}
+
+impl LogSyncer {
+    /// Fsync written records to disk
+    pub fn sync(&self) -> std::io::Result<()> {
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+        self.file.sync_data().inspect_err(|_| self.dirty.store(true, Ordering::Release))
+    }
+}
+
+/// Read the records of the log at `path` without modifying it.
+/// A missing log holds no records.
+pub fn read_log(path: &str) -> Result<Vec<LogRecord>, Box<dyn std::error::Error>> {
+    let buffer = match std::fs::read(path) {
+        Ok(buffer) => buffer,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    if buffer.is_empty() {
+        return Ok(Vec::new());
+    }
+    let (records, _, _) = decode_log(&buffer).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(records)
+}
+
+/// Atomically replace the log at `path` with `records` in the current format,
+/// returning the new file positioned at its end
+fn rewrite(path: &str, records: &[LogRecord]) -> Result<File, Box<dyn std::error::Error>> {
+    let mut buffer = header();
+    for (change_index, entry) in records {
+        let encoded = bincode::serialize(&(change_index, entry))?;
+        buffer.extend_from_slice(&(encoded.len() as u32).to_le_bytes());
+        buffer.extend_from_slice(&encoded);
+    }
+
+    let temp_path = format!("{}.tmp", path);
+    let mut temp = File::create(&temp_path)?;
+    temp.write_all(&buffer)?;
+    temp.sync_all()?;
+    std::fs::rename(&temp_path, path)?;
+
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    file.seek(SeekFrom::End(0))?;
+    Ok(file)
+}
+
+/// The log file that accompanies a history file
+pub fn log_path(history_file: &str) -> String {
+    format!("{}.wal", history_file)
+}
+
+fn header() -> Vec<u8> {
+    let mut header = MAGIC.to_vec();
+    header.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    header
+}
+
+/// Check the header and decode complete records, returning them with the number
+/// of bytes they span and the format version. Only the final record may be
+/// incomplete or unreadable, as that is what an interrupted append leaves behind.
+fn decode_log(buffer: &[u8]) -> Result<(Vec<LogRecord>, usize, u32), String> {
+    let body = buffer.strip_prefix(MAGIC).ok_or("Not a write-ahead log")?;
+    let version = body.first_chunk::<4>().ok_or("Write-ahead log header is truncated")?;
+    let version = u32::from_le_bytes(*version);
+    let decode_record: RecordDecoder = match version {
+        // Version 2 lacks the settings entries, which were added at the end
+        FORMAT_VERSION | 2 => |body| Ok(bincode::deserialize(body)?),
+        1 => legacy::migrate_log_record_v1,
+        _ => {
+            return Err(format!(
+                "Write-ahead log format version {} is not supported (expected {})",
+                version, FORMAT_VERSION
+            ));
+        }
+    };
+
+    let mut records = Vec::new();
+    let mut offset = HEADER_LEN;
+    while let Some(header) = buffer.get(offset..offset + 4) {
+        let len = u32::from_le_bytes(header.try_into().expect("Header is 4 bytes")) as usize;
+        let end = offset + 4 + len;
+        let Some(body) = buffer.get(offset + 4..end) else {
+            break;
+        };
+        match decode_record(body) {
+            Ok(record) => records.push(record),
+            Err(_) if end == buffer.len() => break,
+            Err(e) => return Err(format!("Corrupt record at byte {}: {}", offset, e)),
+        }
+        offset = end;
+    }
+
+    Ok((records, offset, version))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(timestamp: u64) -> LogEntry {
+        LogEntry::UndoRollback { timestamp, author: None }
+    }
+
+    fn encode_record(change_index: usize, entry: &LogEntry) -> Vec<u8> {
+        let encoded = bincode::serialize(&(change_index, entry)).unwrap();
+        [&(encoded.len() as u32).to_le_bytes()[..], &encoded].concat()
+    }
+
+    fn timestamps(records: &[LogRecord]) -> Vec<u64> {
+        records
+            .iter()
+            .map(|(_, entry)| match entry {
+                LogEntry::UndoRollback { timestamp, .. } => *timestamp,
+                _ => panic!("unexpected entry"),
+            })
+            .collect()
+    }
+
+    /// A log holding three records, with the byte offset each one starts at
+    fn three_records() -> (Vec<u8>, Vec<usize>) {
+        let mut buffer = header();
+        let mut starts = Vec::new();
+        for index in 0..3 {
+            starts.push(buffer.len());
+            buffer.extend_from_slice(&encode_record(index, &entry(100 + index as u64)));
+        }
+        (buffer, starts)
+    }
+
+    #[test]
+    fn complete_log_decodes() {
+        let (buffer, _) = three_records();
+        let (records, valid_len, version) = decode_log(&buffer).unwrap();
+        assert_eq!(timestamps(&records), [100, 101, 102]);
+        assert_eq!(valid_len, buffer.len());
+        assert_eq!(version, FORMAT_VERSION);
+    }
+
+    #[test]
+    fn truncated_tail_is_dropped() {
+        let (buffer, starts) = three_records();
+        // Cut off in the middle of the last record's body, and of its length
+        for cut in [buffer.len() - 3, starts[2] + 2] {
+            let (records, valid_len, _) = decode_log(&buffer[..cut]).unwrap();
+            assert_eq!(timestamps(&records), [100, 101]);
+            assert_eq!(valid_len, starts[2]);
+        }
+    }
+
+    #[test]
+    fn unreadable_final_record_is_dropped() {
+        let (mut buffer, starts) = three_records();
+        buffer.truncate(starts[2]);
+        buffer.extend_from_slice(&3u32.to_le_bytes());
+        buffer.extend_from_slice(&[0xFF; 3]);
+        let (records, valid_len, _) = decode_log(&buffer).unwrap();
+        assert_eq!(timestamps(&records), [100, 101]);
+        assert_eq!(valid_len, starts[2]);
+    }
+
+    #[test]
+    fn corruption_before_the_last_record_is_refused() {
+        let (mut buffer, starts) = three_records();
+        // Point the first record's variant tag past the last `LogEntry` variant
+        buffer[starts[0] + 4 + 8] = 0xFF;
+        let error = decode_log(&buffer).err().unwrap();
+        assert!(error.starts_with(&format!("Corrupt record at byte {}", starts[0])), "{}", error);
+    }
+
+    #[test]
+    fn version_2_logs_decode() {
+        let (mut buffer, _) = three_records();
+        buffer[MAGIC.len()..HEADER_LEN].copy_from_slice(&2u32.to_le_bytes());
+        let (records, _, version) = decode_log(&buffer).unwrap();
+        assert_eq!(timestamps(&records), [100, 101, 102]);
+        assert_eq!(version, 2);
+    }
+
+    #[test]
+    fn bad_headers_are_refused() {
+        assert!(decode_log(b"PIXIEHST\x02\0\0\0").is_err());
+        assert!(decode_log(&MAGIC[..]).is_err());
+
+        let mut newer = MAGIC.to_vec();
+        newer.extend_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert!(decode_log(&newer).is_err());
+    }
+
+    #[test]
+    fn retracted_records_are_not_reopened() {
+        let path = std::env::temp_dir().join(format!("pixie-wal-test-{}.wal", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+
+        let (mut log, records) = WriteAheadLog::open(path).unwrap();
+        assert!(records.is_empty());
+        log.append(0, &entry(100)).unwrap();
+        log.append(1, &entry(101)).unwrap();
+        log.retract().unwrap();
+        log.append(1, &entry(102)).unwrap();
+        log.syncer().sync().unwrap();
+        drop(log);
+
+        let (log, records) = WriteAheadLog::open(path).unwrap();
+        assert_eq!(timestamps(&records), [100, 102]);
+        assert!(!log.is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+}